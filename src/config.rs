@@ -0,0 +1,231 @@
+use crate::parser;
+use crate::ruby_box;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path;
+use std::str;
+
+pub static FILE_NAME: &str = "constant_sandbox.yml";
+
+/// Project wide settings, read from `constant_sandbox.yml` in the directory
+/// the tool runs from. Every setting is optional.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Ruby version whose built-in constants are ignored, e.g. `"3.1"`.
+    pub ruby_version: Option<String>,
+    pub builtins: BuiltinsConfig,
+    /// Constants provided by each gem, e.g. `faraday: [Faraday]`. Takes
+    /// precedence over what is guessed from `Gemfile.lock` and gem sources.
+    pub gems: BTreeMap<String, Vec<String>>,
+    /// Directory the codebase is analyzed from, relative to this file.
+    pub root: Option<String>,
+    /// Globs of files to analyze as Ruby, replacing the default patterns.
+    pub include: Option<Vec<String>>,
+    /// Globs of files never analyzed, e.g. `vendor/**`.
+    pub exclude: Vec<String>,
+    /// Name of the files that define boxes. `box.yml` by default.
+    pub box_file: Option<String>,
+    /// Globs of files whose references are never reported.
+    pub ignore: Vec<String>,
+    /// Number of files parsed at the same time. One per CPU by default.
+    pub parallelism: Option<usize>,
+    pub format: OutputFormat,
+    /// Layers boxes can be in, lowest first. A box may only use constants
+    /// of boxes in its own or a lower layer.
+    pub layers: Vec<String>,
+    /// Whether dependency cycles between boxes fail `verify`. Defaults to warn,
+    /// as known cycles cannot be recorded in the todo list.
+    pub enforce_cycles: Option<ruby_box::Enforcement>,
+}
+
+pub static DEFAULT_BOX_FILE: &str = "box.yml";
+
+/// How `verify` prints violations.
+#[derive(Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    /// Workflow commands that GitHub Actions shows as annotations.
+    Github,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "github" => Ok(OutputFormat::Github),
+            _ => Err(format!(
+                "unknown output format {}, expected text or github",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuiltinsConfig {
+    /// Which groups of Ruby's own constants to ignore. All of them by default.
+    pub tiers: Vec<parser::Tier>,
+    /// Extra root constants to ignore, e.g. constants of a gem used everywhere.
+    pub add: Vec<String>,
+    /// Built-in roots to check like any other constant.
+    pub remove: Vec<String>,
+}
+
+impl Default for BuiltinsConfig {
+    fn default() -> BuiltinsConfig {
+        BuiltinsConfig {
+            tiers: parser::ALL_TIERS.to_vec(),
+            add: Vec::new(),
+            remove: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn box_file(&self) -> &str {
+        self.box_file.as_deref().unwrap_or(DEFAULT_BOX_FILE)
+    }
+
+    /// The directory to analyze from when this file was read from `dir`.
+    pub fn root_in(&self, dir: &path::Path) -> path::PathBuf {
+        match &self.root {
+            Some(root) => dir.join(root),
+            None => dir.to_path_buf(),
+        }
+    }
+
+    pub fn builtins(&self) -> Result<parser::Builtins, ConfigError> {
+        let version = self
+            .ruby_version
+            .as_deref()
+            .unwrap_or(parser::DEFAULT_RUBY_VERSION);
+        parser::Builtins::new(
+            version,
+            &self.builtins.tiers,
+            &self.builtins.add,
+            &self.builtins.remove,
+        )
+        .map_err(|e| ConfigError {
+            file: path::PathBuf::from(FILE_NAME),
+            location: None,
+            message: e.to_string(),
+        })
+    }
+}
+
+/// A configuration file, `constant_sandbox.yml` or a `box.yml`, that cannot be
+/// used as written.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: path::PathBuf,
+    /// Line and column of the offending value, both 1-based, when known.
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn yaml(file: &path::Path, error: &serde_yaml::Error) -> ConfigError {
+        let mut message = error.to_string();
+        let location = error.location().map(|l| (l.line(), l.column()));
+        if let Some((line, column)) = location {
+            let suffix = format!(" at line {} column {}", line, column);
+            if let Some(stripped) = message.strip_suffix(&suffix) {
+                message = stripped.to_owned();
+            }
+        }
+        ConfigError {
+            file: file.to_path_buf(),
+            location,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.location {
+            Some((line, column)) => write!(
+                f,
+                "{}:{}:{}: {}",
+                self.file.to_string_lossy(),
+                line,
+                column,
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.file.to_string_lossy(), self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads the project config at `path`, or the defaults when there is none.
+pub fn load(path: &path::Path) -> Result<Config, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+    let mut file = File::open(path)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    let contents = str::from_utf8(&contents)?;
+    if contents.trim().is_empty() {
+        return Ok(Config::default());
+    }
+    match serde_yaml::from_str(contents) {
+        Ok(config) => Ok(config),
+        Err(e) => Err(Box::new(ConfigError::yaml(path, &e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_where_the_config_is_wrong() {
+        let e = serde_yaml::from_str::<Config>("ruby_version: \"3.2\"\nbuiltin:\n  add: [Oj]\n")
+            .err()
+            .unwrap();
+        let error = ConfigError::yaml(path::Path::new(FILE_NAME), &e);
+        assert_eq!(error.location, Some((2, 1)));
+        assert!(error
+            .to_string()
+            .starts_with("constant_sandbox.yml:2:1: unknown field `builtin`, expected"));
+    }
+
+    #[test]
+    fn reads_project_settings() {
+        let config: Config = serde_yaml::from_str(
+            "root: app\nexclude: [\"vendor/**\"]\nbox_file: package.yml\nparallelism: 2\nformat: github\nlayers: [platform, domain]\nenforce_cycles: warn\n",
+        )
+        .unwrap();
+        assert_eq!(config.root.as_deref(), Some("app"));
+        assert_eq!(config.include, None);
+        assert_eq!(config.exclude, vec!["vendor/**"]);
+        assert_eq!(config.box_file(), "package.yml");
+        assert_eq!(config.parallelism, Some(2));
+        assert_eq!(config.format, OutputFormat::Github);
+        assert_eq!(config.layers, vec!["platform", "domain"]);
+        assert_eq!(config.enforce_cycles, Some(ruby_box::Enforcement::Warn));
+        assert_eq!(Config::default().box_file(), "box.yml");
+    }
+
+    #[test]
+    fn finds_the_root_from_the_file() {
+        let dir = path::Path::new("proj");
+        let config = Config {
+            root: Some(String::from("app")),
+            ..Default::default()
+        };
+        assert_eq!(config.root_in(dir), path::Path::new("proj/app"));
+        assert_eq!(Config::default().root_in(dir), dir);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::parser;
+use crate::ruby_box::{self, Package};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path;
+
+/// A box using constants of another box.
+pub struct Edge<'a> {
+    pub from: &'a Package,
+    pub to: &'a Package,
+    /// The first reference from `from` to `to`, by file and position.
+    pub example: &'a parser::Relation,
+}
+
+/// Boxes that all depend on each other, directly or through one another.
+pub struct Cycle<'a> {
+    /// Sorted by name.
+    pub packages: Vec<&'a Package>,
+    /// The dependencies between the boxes of the cycle.
+    pub edges: Vec<Edge<'a>>,
+}
+
+impl Cycle<'_> {
+    fn names(&self) -> String {
+        let names: Vec<&str> = self.packages.iter().map(|p| p.name.as_str()).collect();
+        names.join(", ")
+    }
+
+    /// Lines describing the cycle, with an example reference for every
+    /// dependency in it.
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = vec![format!("dependency cycle between boxes {}", self.names())];
+        for edge in &self.edges {
+            lines.push(format!(
+                "  box {} uses box {}: reference {} found in {}:{}",
+                edge.from.name,
+                edge.to.name,
+                edge.example.resolved,
+                edge.example.file.to_string_lossy(),
+                edge.example.span
+            ));
+        }
+        lines
+    }
+
+    /// GitHub Actions workflow commands annotating the example reference of
+    /// every dependency in the cycle at `level`.
+    pub fn annotations(&self, level: &str) -> Vec<String> {
+        self.edges
+            .iter()
+            .map(|edge| {
+                let message = format!(
+                    "box {} uses box {} in a dependency cycle between boxes {}",
+                    edge.from.name,
+                    edge.to.name,
+                    self.names()
+                );
+                let example = edge.example;
+                ruby_box::annotation(level, &example.file, example.span, &message)
+            })
+            .collect()
+    }
+}
+
+/// The dependency cycles between `packages`, made of the references that
+/// cross from one box into another. References to constants the using box
+/// defines too, and between nested boxes that `nested_visibility` lets see
+/// each other, do not count.
+pub fn find<'a>(
+    packages: &'a [Package],
+    owners: &ruby_box::Owners<'a>,
+    defs: &'a [parser::Definition],
+    rels: &'a [parser::Relation],
+    ignores: &[glob::Pattern],
+) -> Vec<Cycle<'a>> {
+    let index: HashMap<&path::Path, usize> = packages
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.path.as_path(), i))
+        .collect();
+    let defined: HashSet<(&path::Path, &str)> = defs
+        .iter()
+        .filter_map(|d| Some((owners.file(&d.file)?.path.as_path(), d.namespace.as_str())))
+        .collect();
+    let mut examples: BTreeMap<(usize, usize), &parser::Relation> = BTreeMap::new();
+    for rel in rels {
+        let (from, to) = match (owners.file(&rel.file), owners.constant(&rel.resolved)) {
+            (Some(from), Some(to)) if from.path != to.path => (from, to),
+            _ => continue,
+        };
+        let enforce_dynamic = from.ruby_box.enforce_dynamic.unwrap_or(true);
+        if (!enforce_dynamic && rel.origin == parser::Origin::Dynamic)
+            || ruby_box::ignored(ignores, &rel.file)
+            || defined.contains(&(from.path.as_path(), rel.resolved.as_str()))
+            || ruby_box::nested_visibility(from, to) != ruby_box::NestedVisibility::Isolated
+        {
+            continue;
+        }
+        let example = examples
+            .entry((index[from.path.as_path()], index[to.path.as_path()]))
+            .or_insert(rel);
+        if (&rel.file, rel.span) < (&example.file, example.span) {
+            *example = rel;
+        }
+    }
+
+    let mut graph = vec![Vec::new(); packages.len()];
+    for &(from, to) in examples.keys() {
+        graph[from].push(to);
+    }
+    let mut cycles: Vec<Cycle> = strongly_connected(&graph)
+        .into_iter()
+        .filter(|component| component.len() > 1)
+        .map(|component| {
+            let mut members: Vec<&Package> = component.iter().map(|&i| &packages[i]).collect();
+            members.sort_by(|a, b| a.name.cmp(&b.name));
+            let mut edges: Vec<Edge> = examples
+                .iter()
+                .filter(|((from, to), _)| component.contains(from) && component.contains(to))
+                .map(|(&(from, to), example)| Edge {
+                    from: &packages[from],
+                    to: &packages[to],
+                    example,
+                })
+                .collect();
+            edges.sort_by(|a, b| (&a.from.name, &a.to.name).cmp(&(&b.from.name, &b.to.name)));
+            Cycle {
+                packages: members,
+                edges,
+            }
+        })
+        .collect();
+    cycles.sort_by(|a, b| a.packages[0].name.cmp(&b.packages[0].name));
+    cycles
+}
+
+struct Tarjan<'g> {
+    graph: &'g [Vec<usize>],
+    next_index: usize,
+    indices: Vec<Option<usize>>,
+    lowlinks: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, node: usize) {
+        self.indices[node] = Some(self.next_index);
+        self.lowlinks[node] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+
+        for &next in &self.graph[node] {
+            match self.indices[next] {
+                None => {
+                    self.visit(next);
+                    self.lowlinks[node] = self.lowlinks[node].min(self.lowlinks[next]);
+                }
+                Some(index) if self.on_stack[next] => {
+                    self.lowlinks[node] = self.lowlinks[node].min(index);
+                }
+                Some(_) => {}
+            }
+        }
+
+        if Some(self.lowlinks[node]) == self.indices[node] {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+/// The strongly connected components of `graph`, given as the nodes each
+/// node points to, found with Tarjan's algorithm.
+fn strongly_connected(graph: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut tarjan = Tarjan {
+        graph,
+        next_index: 0,
+        indices: vec![None; graph.len()],
+        lowlinks: vec![0; graph.len()],
+        stack: Vec::new(),
+        on_stack: vec![false; graph.len()],
+        components: Vec::new(),
+    };
+    for node in 0..graph.len() {
+        if tarjan.indices[node].is_none() {
+            tarjan.visit(node);
+        }
+    }
+    tarjan.components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str) -> Package {
+        Package::new(
+            format!("lib/{}/box.yml", name).into(),
+            ruby_box::RubyBox::default(),
+        )
+    }
+
+    #[test]
+    fn finds_cycles_between_boxes() {
+        let packages: Vec<Package> = ["a", "b", "c", "d"].iter().map(|n| package(n)).collect();
+        let defs: Vec<parser::Definition> = ["A", "B", "C", "D"]
+            .iter()
+            .map(|c| {
+                let file = format!("lib/{}/x.rb", c.to_lowercase());
+                parser::Definition::new(c, &file)
+            })
+            .collect();
+        let rels = vec![
+            parser::Relation::new("A", "B", "lib/a/x.rb"),
+            parser::Relation::new("A", "B", "lib/a/y.rb"),
+            parser::Relation::new("B", "C", "lib/b/x.rb"),
+            parser::Relation::new("C", "A", "lib/c/x.rb"),
+            parser::Relation::new("C", "D", "lib/c/x.rb"),
+        ];
+        let owners = ruby_box::Owners::all(&packages, &defs, &rels);
+        let cycles = find(&packages, &owners, &defs, &rels, &[]);
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].names(), "lib/a, lib/b, lib/c");
+        assert_eq!(
+            cycles[0].report(),
+            vec![
+                "dependency cycle between boxes lib/a, lib/b, lib/c",
+                "  box lib/a uses box lib/b: reference B found in lib/a/x.rb:0:0",
+                "  box lib/b uses box lib/c: reference C found in lib/b/x.rb:0:0",
+                "  box lib/c uses box lib/a: reference A found in lib/c/x.rb:0:0",
+            ]
+        );
+
+        let ignores = vec![glob::Pattern::new("lib/c/**").unwrap()];
+        assert!(find(&packages, &owners, &defs, &rels, &ignores).is_empty());
+    }
+
+    #[test]
+    fn skips_shared_and_nested_constants() {
+        let mut packages: Vec<Package> = ["a", "b", "c", "a/inner"]
+            .iter()
+            .map(|n| package(n))
+            .collect();
+        packages[0].ruby_box.nested_visibility = Some(ruby_box::NestedVisibility::All);
+        let mut defs = vec![
+            parser::Definition::new("A", "lib/a/x.rb"),
+            parser::Definition::new("B", "lib/b/x.rb"),
+            parser::Definition::new("C", "lib/c/x.rb"),
+            parser::Definition::new("Inner", "lib/a/inner/x.rb"),
+            parser::Definition::new("Shared", "lib/b/shared.rb"),
+            parser::Definition::new("Shared", "lib/a/shared.rb"),
+        ];
+        let rels = vec![
+            parser::Relation::new("A", "B", "lib/a/x.rb"),
+            parser::Relation::new("A", "C", "lib/a/x.rb"),
+            parser::Relation::new("A", "Inner", "lib/a/x.rb"),
+            parser::Relation::new("Inner", "A", "lib/a/inner/x.rb"),
+            parser::Relation::new("B", "Shared", "lib/b/x.rb"),
+            parser::Relation::new("C", "Shared", "lib/c/x.rb"),
+        ];
+        for _ in 0..2 {
+            let owners = ruby_box::Owners::all(&packages, &defs, &rels);
+            let cycles = find(&packages, &owners, &defs, &rels, &[]);
+            assert_eq!(cycles.len(), 1);
+            assert_eq!(cycles[0].names(), "lib/a, lib/c");
+            defs.reverse();
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use crate::parser;
+use std::collections::{HashMap, HashSet};
+use std::path;
+
+pub static LOCKFILE: &str = "Gemfile.lock";
+
+/// Knows which gem provides which top-level constants.
+///
+/// Constants declared in the project config win, then roots learned from a
+/// gem's sources. Otherwise a root is matched to a locked gem by name, so
+/// `ActiveSupport` belongs to `activesupport` and `Sidekiq` to `sidekiq`.
+#[derive(Default)]
+pub struct GemIndex {
+    /// Locked gems and their versions, in lockfile order.
+    pub gems: Vec<(String, String)>,
+    roots: HashMap<String, String>,
+    declared: Vec<(String, String)>,
+}
+
+impl GemIndex {
+    pub fn from_lockfile(contents: &str) -> GemIndex {
+        GemIndex {
+            gems: locked_gems(contents),
+            ..Default::default()
+        }
+    }
+
+    /// Declares that `gem` provides `constants` and everything below them.
+    pub fn declare(&mut self, gem: &str, constants: &[String]) {
+        for constant in constants {
+            self.declared.push((constant.clone(), gem.to_owned()));
+        }
+    }
+
+    /// Source directories of the locked gems found under any of the
+    /// `GEM_HOME`-style `homes`, e.g. `vendor/bundle/ruby/3.0.0`.
+    pub fn gem_dirs(&self, homes: &[path::PathBuf]) -> Vec<(String, path::PathBuf)> {
+        let mut dirs = Vec::new();
+        for (name, version) in &self.gems {
+            let found = homes
+                .iter()
+                .map(|home| home.join("gems").join(format!("{}-{}", name, version)))
+                .find(|dir| dir.is_dir());
+            if let Some(dir) = found {
+                dirs.push((name.clone(), dir));
+            }
+        }
+        dirs
+    }
+
+    /// Learns the roots defined by the gem sources in `dirs`. A root opened by
+    /// several gems stays with the first one, or the one named after it.
+    pub fn learn(&mut self, dirs: &[(String, path::PathBuf)], defs: &[parser::Definition]) {
+        for def in defs {
+            let gem = match dirs.iter().find(|(_, dir)| def.file.starts_with(dir)) {
+                Some((gem, _)) => gem,
+                None => continue,
+            };
+            let root = root(&def.namespace);
+            let named_after = normalize(root) == normalize(gem);
+            if named_after || !self.roots.contains_key(root) {
+                self.roots.insert(root.to_owned(), gem.clone());
+            }
+        }
+    }
+
+    pub fn gem_for(&self, constant: &str) -> Option<&str> {
+        let declared = self
+            .declared
+            .iter()
+            .filter(|(prefix, _)| {
+                constant == prefix
+                    || constant
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len());
+        if let Some((_, gem)) = declared {
+            return Some(gem);
+        }
+        let root = root(constant);
+        if let Some(gem) = self.roots.get(root) {
+            return Some(gem);
+        }
+        let root = normalize(root);
+        self.gems
+            .iter()
+            .find(|(name, _)| normalize(name) == root)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// Marks relations to constants that come from a gem rather than the codebase.
+pub fn attribute(index: &GemIndex, defs: &[parser::Definition], rels: &mut [parser::Relation]) {
+    let defined: HashSet<&str> = defs.iter().map(|d| d.namespace.as_str()).collect();
+    for rel in rels.iter_mut() {
+        if !defined.contains(rel.resolved.as_str()) {
+            rel.gem = index.gem_for(&rel.resolved).map(String::from);
+        }
+    }
+}
+
+/// Gem names and versions from the `specs:` sections of a `Gemfile.lock`.
+fn locked_gems(contents: &str) -> Vec<(String, String)> {
+    let mut gems = Vec::new();
+    let mut in_specs = false;
+    for line in contents.lines() {
+        if !line.starts_with(' ') {
+            in_specs = false;
+            continue;
+        }
+        if line.trim() == "specs:" {
+            in_specs = true;
+            continue;
+        }
+        // gems are indented by four spaces, their dependencies by six
+        if !in_specs || !line.starts_with("    ") || line.starts_with("     ") {
+            continue;
+        }
+        let mut parts = line.trim().splitn(2, ' ');
+        let name = parts.next().unwrap_or_default();
+        let version = parts
+            .next()
+            .unwrap_or_default()
+            .trim_matches(|c| c == '(' || c == ')');
+        // platform specific builds install as e.g. nokogiri-1.11.0-x86_64-linux
+        gems.push((name.to_owned(), version.to_owned()));
+    }
+    gems
+}
+
+fn root(constant: &str) -> &str {
+    constant.split("::").next().unwrap_or(constant)
+}
+
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static LOCK: &str = "GIT
+  remote: https://github.com/example/internal_client.git
+  revision: 0123456
+  specs:
+    internal_client (0.1.0)
+      faraday
+
+GEM
+  remote: https://rubygems.org/
+  specs:
+    activesupport (6.1.0)
+      concurrent-ruby (~> 1.0, >= 1.0.2)
+    concurrent-ruby (1.1.8)
+    faraday (1.3.0)
+    nokogiri (1.11.1-x86_64-linux)
+    sidekiq (6.1.3)
+
+PLATFORMS
+  ruby
+
+DEPENDENCIES
+  sidekiq
+";
+
+    #[test]
+    fn reads_locked_gems() {
+        let index = GemIndex::from_lockfile(LOCK);
+        let names: Vec<&str> = index.gems.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "internal_client",
+                "activesupport",
+                "concurrent-ruby",
+                "faraday",
+                "nokogiri",
+                "sidekiq"
+            ]
+        );
+        assert_eq!(index.gems[4].1, "1.11.1-x86_64-linux");
+    }
+
+    #[test]
+    fn attributes_constants_to_gems() {
+        let mut index = GemIndex::from_lockfile(LOCK);
+        let dirs = vec![(
+            String::from("concurrent-ruby"),
+            path::PathBuf::from("vendor/gems/concurrent-ruby-1.1.8"),
+        )];
+        index.learn(
+            &dirs,
+            &[parser::Definition::new(
+                "Concurrent::Map",
+                "vendor/gems/concurrent-ruby-1.1.8/lib/concurrent/map.rb",
+            )],
+        );
+        assert_eq!(index.gem_for("Sidekiq::Worker"), Some("sidekiq"));
+        assert_eq!(
+            index.gem_for("ActiveSupport::Concern"),
+            Some("activesupport")
+        );
+        assert_eq!(index.gem_for("InternalClient"), Some("internal_client"));
+        assert_eq!(index.gem_for("Concurrent::Future"), Some("concurrent-ruby"));
+        assert_eq!(index.gem_for("Billing::Invoice"), None);
+    }
+
+    #[test]
+    fn declared_constants_win() {
+        let mut index = GemIndex::from_lockfile(LOCK);
+        index.declare("internal_client", &[String::from("Sidekiq::Pro")]);
+        index.declare("aws-sdk-s3", &[String::from("Aws::S3")]);
+        assert_eq!(
+            index.gem_for("Sidekiq::Pro::Batch"),
+            Some("internal_client")
+        );
+        assert_eq!(index.gem_for("Sidekiq::Worker"), Some("sidekiq"));
+        assert_eq!(index.gem_for("Aws::S3::Client"), Some("aws-sdk-s3"));
+        assert_eq!(index.gem_for("Aws::S3Control"), None);
+    }
+}
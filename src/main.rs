@@ -1,32 +1,74 @@
-use clap::{value_t_or_exit, values_t, App, Arg, SubCommand};
+use clap::{value_t, value_t_or_exit, values_t, App, Arg, SubCommand};
 
 use crossbeam_channel::bounded;
 use glob::glob;
-use regex::Regex;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::Read;
 use std::path;
-use std::str;
 use std::thread;
+mod config;
+mod cycles;
+mod gems;
 mod parser;
+mod pattern;
 mod ruby_box;
+mod todo;
+
+/// Errors that end the run with their own exit code, so CI can tell
+/// box violations apart from other failures.
+#[derive(Debug)]
+enum Failure {
+    Violations,
+    Cycles(usize),
+    UnparsableFiles(usize),
+    FileErrors(usize),
+    InvalidConfig(usize),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Violations | Failure::Cycles(_) => 1,
+            Failure::UnparsableFiles(_) => 2,
+            Failure::FileErrors(_) => 3,
+            Failure::InvalidConfig(_) => 4,
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Violations => write!(f, "found box violations"),
+            Failure::Cycles(count) => write!(f, "found {} dependency cycles between boxes", count),
+            Failure::UnparsableFiles(count) => write!(f, "{} files could not be parsed", count),
+            Failure::FileErrors(count) => write!(f, "{} files could not be analyzed", count),
+            Failure::InvalidConfig(count) => write!(f, "{} configuration files are invalid", count),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
 
 fn parse_ruby(
-) -> Result<(Vec<parser::Definition>, Vec<parser::Relation>), Box<dyn std::error::Error>> {
-    let cpus = num_cpus::get();
+    sources: &parser::Sources,
+    options: &parser::Options,
+    jobs: usize,
+) -> Result<parser::Codebase, Box<dyn std::error::Error>> {
+    let (files, mut glob_errors) = sources.files()?;
 
     let (work_tx, work_rx) = bounded(0);
     let (collect_tx, collect_rx) = bounded(0);
     let (results_tx, results_rx) = bounded(0);
 
     let mut children = Vec::new();
-    for _id in 0..cpus {
+    for _id in 0..jobs {
         let thread_work_rx = work_rx.clone();
         let thread_results_tx = collect_tx.clone();
+        let thread_options = options.clone();
         let child = thread::spawn(move || {
-            parser::worker(thread_work_rx, thread_results_tx).unwrap();
+            parser::worker(thread_work_rx, thread_results_tx, thread_options);
         });
         children.push(child);
     }
@@ -41,8 +83,7 @@ fn parse_ruby(
         drop(results_tx);
     });
 
-    for entry in glob("**/*.rb").expect("Failed to read glob pattern") {
-        let path = entry?;
+    for path in files {
         work_tx.send(path)?;
     }
 
@@ -59,23 +100,183 @@ fn parse_ruby(
         .join()
         .expect("results collector panicked");
 
-    let mut defs = Vec::new();
-    let mut rels = Vec::new();
-    for mut result in results {
-        for def in result.definitions.drain(0..) {
-            defs.push(def);
-        }
-        for rel in result.relations.drain(0..) {
-            rels.push(rel);
-        }
+    let mut codebase = parser::Codebase::default();
+    for result in results {
+        codebase.add(result);
+    }
+    codebase.errors.append(&mut glob_errors);
+    parser::resolve(&codebase.definitions, &mut codebase.relations);
+    parser::suppress(&codebase.suppressions, &mut codebase.relations);
+    Ok(codebase)
+}
+
+/// Parses the codebase, drops references to Ruby's own constants and
+/// prints what the parser complained about.
+fn analyze(
+    config: &config::Config,
+    matches: &clap::ArgMatches,
+) -> Result<parser::Codebase, Box<dyn std::error::Error>> {
+    let builtins = config.builtins()?;
+    let mut codebase = parse_ruby(
+        &sources(config, matches),
+        &parser_options(matches),
+        jobs(config),
+    )?;
+    codebase.remove_builtins(&builtins);
+    let index = gem_index(config, &mut codebase, matches)?;
+    gems::attribute(&index, &codebase.definitions, &mut codebase.relations);
+    for diagnostic in &codebase.diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    Ok(codebase)
+}
+
+/// The gems of the project and the constants they define, from
+/// `Gemfile.lock` and the `gems` mapping of `constant_sandbox.yml`.
+fn gem_index(
+    config: &config::Config,
+    codebase: &mut parser::Codebase,
+    matches: &clap::ArgMatches,
+) -> Result<gems::GemIndex, Box<dyn std::error::Error>> {
+    let mut index = locked_gem_index(config, codebase, matches)?;
+    for (gem, constants) in &config.gems {
+        index.declare(gem, constants);
+    }
+    Ok(index)
+}
+
+/// Reads `Gemfile.lock` and learns which constants the locked gems define
+/// from their installed sources. Gems vendored inside the project were
+/// already parsed with it and are moved out of the codebase.
+fn locked_gem_index(
+    config: &config::Config,
+    codebase: &mut parser::Codebase,
+    matches: &clap::ArgMatches,
+) -> Result<gems::GemIndex, Box<dyn std::error::Error>> {
+    let lockfile = path::Path::new(gems::LOCKFILE);
+    if !lockfile.is_file() {
+        return Ok(gems::GemIndex::default());
+    }
+    let mut index = gems::GemIndex::from_lockfile(&std::fs::read_to_string(lockfile)?);
+
+    let mut homes: Vec<path::PathBuf> = values_t!(matches.values_of("gem-home"), String)
+        .unwrap_or_default()
+        .into_iter()
+        .map(path::PathBuf::from)
+        .collect();
+    homes.extend(glob("vendor/bundle/ruby/*")?.flatten());
+    let dirs = index.gem_dirs(&homes);
+
+    let vendored = codebase.split_off(|file| dirs.iter().any(|(_, dir)| file.starts_with(dir)));
+    index.learn(&dirs, &vendored.definitions);
+
+    // gems installed outside the codebase, or excluded from it
+    let outside: Vec<String> = dirs
+        .iter()
+        .filter(|(_, dir)| !vendored.definitions.iter().any(|d| d.file.starts_with(dir)))
+        .filter_map(|(_, dir)| dir.to_str())
+        .map(|dir| format!("{}/lib/**/*.rb", glob::Pattern::escape(dir)))
+        .collect();
+    if !outside.is_empty() {
+        let sources = parser::Sources {
+            patterns: outside,
+            filenames: Vec::new(),
+            exclude: Vec::new(),
+        };
+        let installed = parse_ruby(&sources, &parser::Options::default(), jobs(config))?;
+        index.learn(&dirs, &installed.definitions);
+    }
+    Ok(index)
+}
+
+/// Prints the files that could not be analyzed. With `--strict` they fail the run.
+fn report_file_errors(
+    codebase: &parser::Codebase,
+    matches: &clap::ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for error in &codebase.errors {
+        eprintln!("could not analyze {}", error);
+    }
+    if !codebase.errors.is_empty() && matches.is_present("strict") {
+        Err(Box::new(Failure::FileErrors(codebase.errors.len())))
+    } else {
+        Ok(())
+    }
+}
+
+fn sources(config: &config::Config, matches: &clap::ArgMatches) -> parser::Sources {
+    let mut sources = parser::Sources::default();
+    if let Some(patterns) = &config.include {
+        sources.patterns = patterns.clone();
+    }
+    if let Ok(filenames) = values_t!(matches.values_of("ruby-filename"), String) {
+        sources.filenames = filenames;
+    }
+    sources.exclude = config.exclude.clone();
+    sources
+}
+
+fn jobs(config: &config::Config) -> usize {
+    config.parallelism.unwrap_or_else(num_cpus::get).max(1)
+}
+
+fn ignores(config: &config::Config) -> Result<Vec<glob::Pattern>, glob::PatternError> {
+    config
+        .ignore
+        .iter()
+        .map(|v| glob::Pattern::new(v))
+        .collect()
+}
+
+/// Reads `constant_sandbox.yml`, from `--root` when given, lets command line
+/// flags override it and moves to the configured root directory, which is
+/// relative to `--root`.
+fn load_config(matches: &clap::ArgMatches) -> Result<config::Config, Box<dyn std::error::Error>> {
+    let root = matches.value_of("root");
+    let dir = path::Path::new(root.unwrap_or_default());
+    let mut config = config::load(&dir.join(config::FILE_NAME))?;
+    if root.is_some() {
+        config.root = Some(config.root_in(dir).to_string_lossy().into_owned());
+    }
+    if let Ok(patterns) = values_t!(matches.values_of("ruby-pattern"), String) {
+        config.include = Some(patterns);
+    }
+    if let Ok(exclude) = values_t!(matches.values_of("exclude"), String) {
+        config.exclude = exclude;
+    }
+    if let Ok(ignore) = values_t!(matches.values_of("ignore"), String) {
+        config.ignore = ignore;
+    }
+    if let Some(box_file) = matches.value_of("box-file") {
+        config.box_file = Some(box_file.to_owned());
+    }
+    if matches.is_present("jobs") {
+        config.parallelism = Some(value_t!(matches.value_of("jobs"), usize)?);
+    }
+    if let Some(version) = matches.value_of("ruby-version") {
+        config.ruby_version = Some(version.to_owned());
+    }
+    if let Some(format) = matches.value_of("format") {
+        config.format = format.parse()?;
+    }
+    if let Some(root) = &config.root {
+        std::env::set_current_dir(root).map_err(|e| format!("{}: {}", root, e))?;
+    }
+    Ok(config)
+}
+
+fn parser_options(matches: &clap::ArgMatches) -> parser::Options {
+    parser::Options {
+        rails: matches.is_present("rails"),
     }
-    Ok((defs, rels))
 }
 
 enum Command<'a> {
     Init(&'a clap::ArgMatches<'a>),
     Inspect(&'a clap::ArgMatches<'a>),
     Verify(&'a clap::ArgMatches<'a>),
+    UpdateTodo(&'a clap::ArgMatches<'a>),
+    Cycles(&'a clap::ArgMatches<'a>),
 }
 
 fn subcommand<'a>(app: &'a clap::ArgMatches) -> Result<Command<'a>, Box<dyn std::error::Error>> {
@@ -83,41 +284,121 @@ fn subcommand<'a>(app: &'a clap::ArgMatches) -> Result<Command<'a>, Box<dyn std:
         ("init", Some(m)) => Ok(Command::Init(m)),
         ("inspect", Some(m)) => Ok(Command::Inspect(m)),
         ("verify", Some(m)) => Ok(Command::Verify(m)),
+        ("update-todo", Some(m)) => Ok(Command::UpdateTodo(m)),
+        ("cycles", Some(m)) => Ok(Command::Cycles(m)),
         (_, None) => Ok(Command::Verify(app)),
         (_, Some(_)) => Err("recieved a unknown subcommand".into()),
     }
 }
 
-fn command_init(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
-    let (defs, rels) = parse_ruby().unwrap();
-    let box_str = value_t_or_exit!(matches.value_of("box"), String);
-    let ref path: path::PathBuf = box_str.into();
-    if path.exists() {
-        std::fs::remove_file(path)?;
+/// Loads every `box.yml` in the codebase. Invalid boxes are all reported
+/// before giving up.
+fn load_packages(
+    config: &config::Config,
+) -> Result<Vec<ruby_box::Package>, Box<dyn std::error::Error>> {
+    let (packages, invalid) = read_packages(config, None)?;
+    for error in &invalid {
+        eprintln!("{}", error);
+    }
+    if !invalid.is_empty() {
+        return Err(Box::new(Failure::InvalidConfig(invalid.len())));
     }
+    Ok(packages)
+}
 
-    let rb = ruby_box::RubyBox {
-        imports: Vec::new(),
-        exports: Vec::new(),
-    };
-    let ignores: Result<Vec<glob::Pattern>, glob::PatternError> =
-        if let Ok(values) = values_t!(matches.values_of("ignore"), String) {
-            values.into_iter().map(|v| glob::Pattern::new(&v)).collect()
-        } else {
-            Ok(Vec::new())
-        };
-    let ref errors = ruby_box::enforce_box(path, rb, &defs, &rels, &ignores?);
-    println!("updating box {:?}", path);
+/// Every `box.yml` in the codebase that could be read, and what is wrong
+/// with the others. The box at `target` is kept as empty when invalid.
+fn read_packages(
+    config: &config::Config,
+    target: Option<&path::Path>,
+) -> Result<(Vec<ruby_box::Package>, Vec<config::ConfigError>), Box<dyn std::error::Error>> {
+    let mut packages = Vec::new();
+    let mut invalid = Vec::new();
+    let pattern = format!("**/{}", glob::Pattern::escape(config.box_file()));
+    for entry in glob(&pattern)? {
+        let path = entry?;
+        match ruby_box::load(&path) {
+            Ok(rb) => packages.push(ruby_box::Package::new(path, rb)),
+            Err(_) if Some(path.as_path()) == target => {
+                packages.push(ruby_box::Package::new(path, Default::default()))
+            }
+            Err(e) => match e.downcast::<config::ConfigError>() {
+                Ok(e) => invalid.push(*e),
+                Err(e) => return Err(e),
+            },
+        }
+    }
+    if invalid.is_empty() {
+        invalid = ruby_box::check_packages(&packages, &config.layers);
+    }
+    Ok((packages, invalid))
+}
+
+/// The violations of `package`, of its imports, exports and layer. Violations
+/// suppressed by a comment are left out and the comment is added to `used`
+/// when given.
+fn check_box(
+    config: &config::Config,
+    package: &ruby_box::Package,
+    owners: &ruby_box::Owners,
+    codebase: &parser::Codebase,
+    ignores: &[glob::Pattern],
+    mut used: Option<&mut parser::UsedSuppressions>,
+) -> Vec<ruby_box::BoxViolation> {
+    let mut violations = ruby_box::enforce_box(
+        package,
+        owners,
+        &codebase.definitions,
+        &codebase.relations,
+        ignores,
+    );
+    violations.extend(ruby_box::enforce_layers(
+        package,
+        owners,
+        &config.layers,
+        &codebase.relations,
+        ignores,
+    ));
+    violations.retain(|v| match v.rel.suppressed_by {
+        Some(span) => {
+            if let Some(used) = used.as_deref_mut() {
+                used.insert((v.rel.file.clone(), span));
+            }
+            false
+        }
+        None => true,
+    });
+    violations
+}
+
+/// A box that would allow every violation found, keeping the name and layer
+/// of `package`. Layer violations cannot be allowed and are left out.
+fn suggested_box<'a, I>(package: &ruby_box::Package, errors: I) -> ruby_box::RubyBox
+where
+    I: IntoIterator<Item = &'a ruby_box::BoxViolation>,
+{
     let mut exports = HashSet::new();
     let mut imports = HashSet::new();
+    let mut gems = HashSet::new();
+    let mut dependencies = HashSet::new();
     for error in errors {
-        match error.dir {
+        match &error.dir {
             ruby_box::ViolationDirection::NonImportedReference => {
-                imports.insert(error.rel.namespace.clone());
+                imports.insert(error.rel.resolved.clone());
             }
-            ruby_box::ViolationDirection::NonExportedReference => {
-                exports.insert(error.rel.namespace.clone());
+            ruby_box::ViolationDirection::UndeclaredGem => {
+                if let Some(gem) = &error.rel.gem {
+                    gems.insert(gem.clone());
+                }
             }
+            ruby_box::ViolationDirection::UndeclaredDependency(name) => {
+                dependencies.insert(name.clone());
+            }
+            ruby_box::ViolationDirection::NonExportedReference
+            | ruby_box::ViolationDirection::FriendOnly(_) => {
+                exports.insert(error.rel.resolved.clone());
+            }
+            ruby_box::ViolationDirection::Layer { .. } => {}
         };
     }
 
@@ -125,113 +406,399 @@ fn command_init(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Er
     exports_vec.sort();
     let exports_vec = exports_vec
         .iter()
-        .map(|s| Regex::new(&s).unwrap())
+        .map(|s| pattern::ConstPattern::Exact(s.clone()).into())
         .collect();
 
     let mut imports_vec: Vec<String> = imports.drain().collect();
     imports_vec.sort();
     let imports_vec = imports_vec
         .iter()
-        .map(|s| Regex::new(&s).unwrap())
+        .map(|s| pattern::ConstPattern::Exact(s.clone()))
         .collect();
 
-    let yaml = serde_yaml::to_string(&ruby_box::RubyBox {
+    let mut gems_vec: Vec<String> = gems.drain().collect();
+    gems_vec.sort();
+
+    let mut dependencies_vec: Vec<String> = dependencies.drain().collect();
+    dependencies_vec.sort();
+
+    ruby_box::RubyBox {
+        name: package.ruby_box.name.clone(),
+        layer: package.ruby_box.layer.clone(),
         exports: exports_vec,
         imports: imports_vec,
-    })?;
+        gems: gems_vec,
+        dependencies: dependencies_vec,
+        ..Default::default()
+    }
+}
+
+/// Prints which files to move into the box's public directory so that the
+/// constants other boxes use are exported without listing them.
+fn suggest_public(
+    package: &ruby_box::Package,
+    errors: &[ruby_box::BoxViolation],
+    defs: &[parser::Definition],
+) {
+    let used: HashSet<&str> = errors
+        .iter()
+        .filter(|e| e.dir == ruby_box::ViolationDirection::NonExportedReference)
+        .map(|e| e.rel.resolved.as_str())
+        .collect();
+    let public_dir = package.public_dir();
+    // the first line defining each constant, by file
+    let mut moves: BTreeMap<&path::Path, BTreeMap<&str, usize>> = BTreeMap::new();
+    for def in defs {
+        if used.contains(def.namespace.as_str())
+            && def.file.starts_with(package.dir())
+            && !def.file.starts_with(&public_dir)
+        {
+            let line = moves
+                .entry(&def.file)
+                .or_default()
+                .entry(&def.namespace)
+                .or_insert(def.span.start_line);
+            *line = (*line).min(def.span.start_line);
+        }
+    }
+    for (file, constants) in moves {
+        let relative = file.strip_prefix(package.dir()).unwrap_or(file);
+        let constants: Vec<String> = constants
+            .into_iter()
+            .map(|(constant, line)| format!("{} (line {})", constant, line))
+            .collect();
+        println!(
+            "move {} to {} to export {}",
+            file.to_string_lossy(),
+            public_dir.join(relative).to_string_lossy(),
+            constants.join(", ")
+        );
+    }
+}
+
+/// The box at `path`, without the rules it had but keeping its name and
+/// layer, and the other boxes of the codebase. The box is about to be
+/// rewritten, so problems with the boxes are only warnings.
+fn package_at(
+    config: &config::Config,
+    path: path::PathBuf,
+) -> Result<(ruby_box::Package, Vec<ruby_box::Package>), Box<dyn std::error::Error>> {
+    let (packages, invalid) = read_packages(config, Some(&path))?;
+    for error in &invalid {
+        eprintln!("warning: {}", error);
+    }
+    let (existing, others): (Vec<_>, Vec<_>) = packages.into_iter().partition(|p| p.path == path);
+    let rb = match existing.into_iter().next() {
+        Some(p) => ruby_box::RubyBox {
+            name: p.ruby_box.name,
+            layer: p.ruby_box.layer,
+            ..Default::default()
+        },
+        None => ruby_box::RubyBox::default(),
+    };
+    Ok((ruby_box::Package::new(path, rb), others))
+}
+
+fn command_init(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(matches)?;
+    let ignores = ignores(&config)?;
+    let box_str = value_t_or_exit!(matches.value_of("box"), String);
+    let (package, packages) = package_at(&config, path::PathBuf::from(box_str))?;
+    let codebase = analyze(&config, matches)?;
+    let path = package.path.as_path();
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+
+    let owners = ruby_box::Owners::new(
+        &package,
+        &packages,
+        &codebase.definitions,
+        &codebase.relations,
+    );
+    let errors = &check_box(&config, &package, &owners, &codebase, &ignores, None);
+    println!("updating box {:?}", path);
+    let rb = if matches.is_present("suggest-public") {
+        suggest_public(&package, errors, &codebase.definitions);
+        suggested_box(
+            &package,
+            errors
+                .iter()
+                .filter(|e| e.dir != ruby_box::ViolationDirection::NonExportedReference),
+        )
+    } else {
+        suggested_box(&package, errors)
+    };
+    let yaml = serde_yaml::to_string(&rb)?;
 
     let mut file = File::create(path)?;
     file.write_all(yaml.as_bytes())?;
-    Ok(())
+    report_file_errors(&codebase, matches)
 }
 
 fn command_inspect(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
-    let (defs, rels) = parse_ruby().unwrap();
+    let config = load_config(matches)?;
+    let ignores = ignores(&config)?;
     let box_str = value_t_or_exit!(matches.value_of("box"), String);
     let mut path: path::PathBuf = box_str.into();
-    path = path.join("box.yml");
+    path = path.join(config.box_file());
+    let (package, packages) = package_at(&config, path)?;
+    let codebase = analyze(&config, matches)?;
 
-    let rb = ruby_box::RubyBox {
-        imports: Vec::new(),
-        exports: Vec::new(),
-    };
-    let ignores: Result<Vec<glob::Pattern>, glob::PatternError> =
-        if let Ok(values) = values_t!(matches.values_of("ignore"), String) {
-            values.into_iter().map(|v| glob::Pattern::new(&v)).collect()
-        } else {
-            Ok(Vec::new())
-        };
-    let ref errors = ruby_box::enforce_box(&path, rb, &defs, &rels, &ignores?);
-    let mut exports = HashSet::new();
-    let mut imports = HashSet::new();
-    for error in errors {
-        println!("{}", error);
-        match error.dir {
-            ruby_box::ViolationDirection::NonImportedReference => {
-                imports.insert(error.rel.namespace.clone());
-            }
-            ruby_box::ViolationDirection::NonExportedReference => {
-                exports.insert(error.rel.namespace.clone());
-            }
-        };
+    let owners = ruby_box::Owners::new(
+        &package,
+        &packages,
+        &codebase.definitions,
+        &codebase.relations,
+    );
+    let errors = &check_box(&config, &package, &owners, &codebase, &ignores, None);
+    for line in ruby_box::report(&package, errors) {
+        println!("{}", line);
     }
 
-    let mut exports_vec: Vec<String> = exports.drain().collect();
-    exports_vec.sort();
-    let exports_vec = exports_vec
-        .iter()
-        .map(|s| Regex::new(&s).unwrap())
-        .collect();
-
-    let mut imports_vec: Vec<String> = imports.drain().collect();
-    imports_vec.sort();
-    let imports_vec = imports_vec
-        .iter()
-        .map(|s| Regex::new(&s).unwrap())
-        .collect();
+    let yaml = serde_yaml::to_string(&suggested_box(&package, errors))?;
+    println!("{}", yaml);
+    report_file_errors(&codebase, matches)
+}
 
-    let yaml = serde_yaml::to_string(&ruby_box::RubyBox {
-        exports: exports_vec,
-        imports: imports_vec,
-    })?;
+/// Prints the violations of `package` in the configured output format.
+fn print_violations(
+    config: &config::Config,
+    package: &ruby_box::Package,
+    violations: &[&ruby_box::BoxViolation],
+    warning: bool,
+) {
+    let violations = violations.iter().copied();
+    match config.format {
+        config::OutputFormat::Text => {
+            let prefix = if warning { "warning: " } else { "" };
+            for line in ruby_box::report(package, violations) {
+                println!("{}{}", prefix, line);
+            }
+        }
+        config::OutputFormat::Github => {
+            let level = if warning { "warning" } else { "error" };
+            for line in ruby_box::annotations(package, violations, level) {
+                println!("{}", line);
+            }
+        }
+    }
+}
 
-    println!("{}", yaml);
-    Ok(())
+/// Prints dependency cycles in the configured output format.
+fn print_cycles(config: &config::Config, cycles: &[cycles::Cycle], warning: bool) {
+    for cycle in cycles {
+        let lines = match config.format {
+            config::OutputFormat::Text => {
+                let prefix = if warning { "warning: " } else { "" };
+                let report = cycle.report().into_iter();
+                report.map(|l| format!("{}{}", prefix, l)).collect()
+            }
+            config::OutputFormat::Github => {
+                cycle.annotations(if warning { "warning" } else { "error" })
+            }
+        };
+        for line in lines {
+            println!("{}", line);
+        }
+    }
 }
 
 fn command_verify(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
-    let (defs, rels) = parse_ruby().unwrap();
+    let config = load_config(matches)?;
+    let ignores = ignores(&config)?;
+    let packages = load_packages(&config)?;
+    let codebase = analyze(&config, matches)?;
     let mut has_errors = false;
-
-    for entry in glob("**/box.yml").expect("Failed to read glob pattern") {
-        let path = entry?;
-        let mut file = File::open(&path)?;
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)?;
-        let rb = ruby_box::parse(str::from_utf8(&contents)?)?;
-        let ignores: Result<Vec<glob::Pattern>, glob::PatternError> =
-            if let Ok(values) = values_t!(matches.values_of("ignore"), String) {
-                values.into_iter().map(|v| glob::Pattern::new(&v)).collect()
-            } else {
-                Ok(Vec::new())
-            };
-        let ref errors = ruby_box::enforce_box(&path, rb, &defs, &rels, &ignores?);
-        println!("verifing box {:?}", path);
-        for error in errors {
-            println!("{}", error);
+    let owners = ruby_box::Owners::all(&packages, &codebase.definitions, &codebase.relations);
+    let mut used = parser::UsedSuppressions::new();
+    for package in &packages {
+        let errors = &check_box(
+            &config,
+            package,
+            &owners,
+            &codebase,
+            &ignores,
+            Some(&mut used),
+        );
+        let todo = todo::load(&todo::path_for(package))?;
+        let (warnings, new_errors): (Vec<_>, Vec<_>) = todo
+            .new_violations(errors)
+            .into_iter()
+            .partition(|v| package.ruby_box.enforcement(&v.dir) == ruby_box::Enforcement::Warn);
+        println!("verifing box {:?}", package.path);
+        print_violations(&config, package, &new_errors, false);
+        print_violations(&config, package, &warnings, true);
+        for (constant, violation, file) in todo.stale_entries(errors) {
+            let message = format!(
+                "{} lists {} ({}) in {} which no longer happens, run update-todo",
+                todo::FILE_NAME,
+                constant,
+                violation,
+                file
+            );
+            match config.format {
+                config::OutputFormat::Text => println!("warning: {}", message),
+                config::OutputFormat::Github => println!("::warning::{}", message),
+            }
         }
-        if errors.len() > 0 && !has_errors {
+        if !new_errors.is_empty() && !has_errors {
             has_errors = true;
         }
     }
-    if has_errors {
-        Err("found box violations".into())
+    let suppressions = codebase
+        .suppressions
+        .iter()
+        .filter(|s| !ruby_box::ignored(&ignores, &s.file));
+    for (suppression, problem) in parser::suppression_problems(suppressions, &used) {
+        match config.format {
+            config::OutputFormat::Text => println!(
+                "{} found in {}:{}",
+                problem,
+                suppression.file.to_string_lossy(),
+                suppression.span
+            ),
+            config::OutputFormat::Github => println!(
+                "{}",
+                ruby_box::annotation("error", &suppression.file, suppression.span, problem)
+            ),
+        }
+        has_errors = true;
+    }
+    let enforce_cycles = config.enforce_cycles.unwrap_or(ruby_box::Enforcement::Warn);
+    if enforce_cycles != ruby_box::Enforcement::Disabled {
+        let warning = enforce_cycles == ruby_box::Enforcement::Warn;
+        let cycles = cycles::find(
+            &packages,
+            &owners,
+            &codebase.definitions,
+            &codebase.relations,
+            &ignores,
+        );
+        print_cycles(&config, &cycles, warning);
+        if !cycles.is_empty() && !warning {
+            has_errors = true;
+        }
+    }
+    report_file_errors(&codebase, matches)?;
+    let unparsable = codebase.unparsable_files().len();
+    if unparsable > 0 && matches.is_present("fail-on-parse-errors") {
+        Err(Box::new(Failure::UnparsableFiles(unparsable)))
+    } else if has_errors {
+        Err(Box::new(Failure::Violations))
     } else {
         Ok(())
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn command_update_todo(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(matches)?;
+    let ignores = ignores(&config)?;
+    let packages = load_packages(&config)?;
+    let codebase = analyze(&config, matches)?;
+    let owners = ruby_box::Owners::all(&packages, &codebase.definitions, &codebase.relations);
+    for package in &packages {
+        let errors = &check_box(&config, package, &owners, &codebase, &ignores, None);
+        let path = todo::path_for(package);
+        if errors.is_empty() {
+            if path.exists() {
+                println!("removing todo {:?}", path);
+                std::fs::remove_file(&path)?;
+            }
+            continue;
+        }
+        println!("updating todo {:?}", path);
+        let yaml = serde_yaml::to_string(&todo::Todo::from_violations(errors))?;
+        let mut file = File::create(&path)?;
+        file.write_all(yaml.as_bytes())?;
+    }
+    report_file_errors(&codebase, matches)
+}
+
+fn command_cycles(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(matches)?;
+    let ignores = ignores(&config)?;
+    let packages = load_packages(&config)?;
+    let codebase = analyze(&config, matches)?;
+    let owners = ruby_box::Owners::all(&packages, &codebase.definitions, &codebase.relations);
+    let cycles = cycles::find(
+        &packages,
+        &owners,
+        &codebase.definitions,
+        &codebase.relations,
+        &ignores,
+    );
+    print_cycles(&config, &cycles, false);
+    report_file_errors(&codebase, matches)?;
+    if cycles.is_empty() {
+        Ok(())
+    } else {
+        Err(Box::new(Failure::Cycles(cycles.len())))
+    }
+}
+
+fn shared_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("ignore")
+            .short("i")
+            .help("glob of tiles to ignore")
+            .takes_value(true)
+            .multiple(true),
+        Arg::with_name("root")
+            .long("root")
+            .help("directory to analyze the codebase from")
+            .takes_value(true),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .help("glob of files never to analyze")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("box-file")
+            .long("box-file")
+            .help("name of the files that define boxes, box.yml by default")
+            .takes_value(true),
+        Arg::with_name("jobs")
+            .short("j")
+            .long("jobs")
+            .help("number of files to parse at the same time, one per CPU by default")
+            .takes_value(true),
+        Arg::with_name("ruby-version")
+            .long("ruby-version")
+            .help("Ruby version whose built-in constants are ignored")
+            .takes_value(true),
+        Arg::with_name("format")
+            .long("format")
+            .help("how verify prints violations, text or github")
+            .takes_value(true),
+        Arg::with_name("ruby-pattern")
+            .long("ruby-pattern")
+            .help("glob of files to analyze as Ruby, replacing the default list")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("ruby-filename")
+            .long("ruby-filename")
+            .help("extensionless file name to analyze as Ruby, replacing the default list")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("strict")
+            .long("strict")
+            .help("exit with code 3 when a file cannot be read or analyzed"),
+        Arg::with_name("gem-home")
+            .long("gem-home")
+            .help("directory gems are installed in, like $GEM_HOME, to learn the constants they define")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("rails")
+            .long("rails")
+            .help("infer constants from Rails association macros like has_many :orders"),
+    ]
+}
+
+fn main() {
     let matches = App::new("constant_sandbox")
         .version("1.0")
         .author("Stephen Solka <solka@hey.com>")
@@ -245,12 +812,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .index(1),
                 )
                 .arg(
-                    Arg::with_name("ignore")
-                        .short("i")
-                        .help("glob of tiles to ignore")
-                        .takes_value(true)
-                        .multiple(true),
-                ),
+                    Arg::with_name("suggest-public")
+                        .long("suggest-public")
+                        .help("suggest moving used constants into the public directory instead of exporting them"),
+                )
+                .args(&shared_args()),
         )
         .subcommand(
             SubCommand::with_name("inspect")
@@ -261,31 +827,46 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .index(1)
                         .required(true),
                 )
-                .arg(
-                    Arg::with_name("ignore")
-                        .short("i")
-                        .help("glob of tiles to ignore")
-                        .takes_value(true)
-                        .multiple(true),
-                ),
+                .args(&shared_args()),
         )
         .subcommand(
             SubCommand::with_name("verify")
                 .about("Verify boxed areas of a codebase comply with defined imports and exports.")
+                .args(&shared_args())
                 .arg(
-                    Arg::with_name("ignore")
-                        .short("i")
-                        .help("glob of tiles to ignore")
-                        .takes_value(true)
-                        .multiple(true),
+                    Arg::with_name("fail-on-parse-errors")
+                        .long("fail-on-parse-errors")
+                        .help("exit with code 2 when a file has Ruby syntax errors"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("update-todo")
+                .about("Record the current violations of every box in its box_todo.yml.")
+                .args(&shared_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("cycles")
+                .about("List the dependency cycles between boxes. Read only.")
+                .args(&shared_args()),
+        )
         .get_matches();
 
-    match subcommand(&matches) {
+    let result = match subcommand(&matches) {
         Ok(Command::Init(matches)) => command_init(matches),
         Ok(Command::Inspect(matches)) => command_inspect(matches),
         Ok(Command::Verify(matches)) => command_verify(matches),
+        Ok(Command::UpdateTodo(matches)) => command_update_todo(matches),
+        Ok(Command::Cycles(matches)) => command_cycles(matches),
         Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        let code = if e.is::<config::ConfigError>() {
+            Failure::InvalidConfig(1).exit_code()
+        } else {
+            e.downcast_ref::<Failure>().map_or(1, Failure::exit_code)
+        };
+        std::process::exit(code);
     }
 }
@@ -1,138 +1,364 @@
-pub static RUBY: &'static [&str] = &[
-    "KeyError",
-    "Queue",
-    "IndexError",
-    "RangeError",
-    "ScriptError",
-    "SyntaxError",
-    "LoadError",
-    "ClosedQueueError",
-    "Mutex",
-    "NotImplementedError",
-    "NameError",
-    "SizedQueue",
-    "NoMethodError",
-    "ConditionVariable",
-    "RuntimeError",
-    "FrozenError",
-    "SecurityError",
-    "NoMemoryError",
-    "EncodingError",
-    "Random",
-    "NoMatchingPatternError",
-    "String",
-    "SystemCallError",
-    "Errno",
-    "NilClass",
-    "Warning",
-    "Integer",
-    "RDoc",
-    "Array",
-    "Hash",
-    "Tempfile",
-    "Signal",
-    "TimeoutError",
-    "IPSocket",
-    "IPAddr",
-    "Proc",
-    "CROSS_COMPILING",
-    "Process",
-    "Monitor",
-    "MonitorMixin",
-    "LocalJumpError",
-    "SystemStackError",
-    "Method",
-    "Reline",
-    "TrueClass",
-    "FalseClass",
-    "Encoding",
-    "UncaughtThrowError",
-    "STDIN",
-    "STDOUT",
-    "STDERR",
-    "UnboundMethod",
-    "Binding",
-    "Ripper",
-    "Math",
-    "ARGF",
-    "Comparable",
-    "FileTest",
-    "File",
-    "Enumerable",
-    "Set",
-    "ZeroDivisionError",
-    "FloatDomainError",
-    "Numeric",
-    "URI",
-    "Delegator",
-    "GC",
-    "SimpleDelegator",
-    "FileUtils",
-    "ENV",
-    "SortedSet",
-    "ObjectSpace",
-    "RbConfig",
-    "Struct",
-    "Etc",
-    "Fixnum",
-    "RegexpError",
-    "Float",
-    "Regexp",
-    "JSON",
-    "Enumerator",
-    "Bignum",
-    "MatchData",
-    "Fiber",
-    "FiberError",
-    "StopIteration",
-    "Rational",
-    "Ractor",
-    "Timeout",
-    "Gem",
-    "DidYouMean",
-    "RubyVM",
-    "Complex",
-    "SingleForwardable",
-    "Forwardable",
-    "OpenStruct",
-    "Thread",
-    "TOPLEVEL_BINDING",
-    "RUBY_VERSION",
-    "RUBY_RELEASE_DATE",
-    "RUBY_PLATFORM",
-    "RUBY_PATCHLEVEL",
-    "RUBY_REVISION",
-    "Dir",
-    "RUBY_ENGINE",
-    "RUBY_COPYRIGHT",
-    "RUBY_ENGINE_VERSION",
-    "TracePoint",
-    "TSort",
-    "IRB",
-    "Marshal",
-    "ARGV",
-    "RUBY_DESCRIPTION",
-    "Range",
-    "UnicodeNormalize",
-    "IOError",
-    "EOFError",
-    "Time",
-    "Symbol",
-    "RubyLex",
-    "Exception",
-    "BasicObject",
-    "ThreadGroup",
-    "IO",
-    "Module",
-    "Object",
-    "SystemExit",
-    "Class",
-    "ThreadError",
-    "Kernel",
-    "SignalException",
-    "Interrupt",
-    "StandardError",
-    "TypeError",
-    "ArgumentError",
-    "RUBYGEMS_ACTIVATION_MONITOR",
+use serde::{Deserialize, Serialize};
+
+/// Ruby versions with built-in constant lists, oldest first.
+pub static RUBY_VERSIONS: &[&str] = &["2.7", "3.0", "3.1", "3.2", "3.3", "3.4"];
+
+pub static DEFAULT_RUBY_VERSION: &str = "3.0";
+
+/// Groups of constants that come with Ruby itself.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tier {
+    /// Defined by the interpreter or loaded at boot, e.g. `String` or `Gem`.
+    Core,
+    /// Standard libraries that need a `require`, e.g. `Socket` or `Ripper`.
+    Stdlib,
+    /// Standard libraries shipped as default gems, e.g. `JSON` or `URI`.
+    DefaultGems,
+}
+
+pub static ALL_TIERS: &[Tier] = &[Tier::Core, Tier::Stdlib, Tier::DefaultGems];
+
+type Version = (u32, u32);
+
+const ANY: Version = (0, 0);
+const NEVER: Version = (u32::MAX, 0);
+
+/// A root constant available from the first version up to, but not
+/// including, the second.
+type Entry = (&'static str, Version, Version);
+
+static CORE: &[Entry] = &[
+    ("ARGF", ANY, NEVER),
+    ("ARGV", ANY, NEVER),
+    ("ArgumentError", ANY, NEVER),
+    ("Array", ANY, NEVER),
+    ("BasicObject", ANY, NEVER),
+    ("Bignum", ANY, (3, 2)),
+    ("Binding", ANY, NEVER),
+    ("CROSS_COMPILING", ANY, NEVER),
+    ("Class", ANY, NEVER),
+    ("ClosedQueueError", ANY, NEVER),
+    ("Comparable", ANY, NEVER),
+    ("Complex", ANY, NEVER),
+    ("ConditionVariable", ANY, NEVER),
+    ("Data", (3, 2), NEVER),
+    ("DidYouMean", ANY, NEVER),
+    ("Dir", ANY, NEVER),
+    ("ENV", ANY, NEVER),
+    ("EOFError", ANY, NEVER),
+    ("Encoding", ANY, NEVER),
+    ("EncodingError", ANY, NEVER),
+    ("Enumerable", ANY, NEVER),
+    ("Enumerator", ANY, NEVER),
+    ("Errno", ANY, NEVER),
+    ("ErrorHighlight", (3, 1), NEVER),
+    ("Exception", ANY, NEVER),
+    ("FalseClass", ANY, NEVER),
+    ("Fiber", ANY, NEVER),
+    ("FiberError", ANY, NEVER),
+    ("File", ANY, NEVER),
+    ("FileTest", ANY, NEVER),
+    ("Fixnum", ANY, (3, 2)),
+    ("Float", ANY, NEVER),
+    ("FloatDomainError", ANY, NEVER),
+    ("FrozenError", ANY, NEVER),
+    ("GC", ANY, NEVER),
+    ("Gem", ANY, NEVER),
+    ("Hash", ANY, NEVER),
+    ("IO", ANY, NEVER),
+    ("IOError", ANY, NEVER),
+    ("IndexError", ANY, NEVER),
+    ("Integer", ANY, NEVER),
+    ("Interrupt", ANY, NEVER),
+    ("Kernel", ANY, NEVER),
+    ("KeyError", ANY, NEVER),
+    ("LoadError", ANY, NEVER),
+    ("LocalJumpError", ANY, NEVER),
+    ("Marshal", ANY, NEVER),
+    ("MatchData", ANY, NEVER),
+    ("Math", ANY, NEVER),
+    ("Method", ANY, NEVER),
+    ("Module", ANY, NEVER),
+    ("Mutex", ANY, NEVER),
+    ("NameError", ANY, NEVER),
+    ("NilClass", ANY, NEVER),
+    ("NoMatchingPatternError", ANY, NEVER),
+    ("NoMatchingPatternKeyError", (3, 1), NEVER),
+    ("NoMemoryError", ANY, NEVER),
+    ("NoMethodError", ANY, NEVER),
+    ("NotImplementedError", ANY, NEVER),
+    ("Numeric", ANY, NEVER),
+    ("Object", ANY, NEVER),
+    ("ObjectSpace", ANY, NEVER),
+    ("Proc", ANY, NEVER),
+    ("Process", ANY, NEVER),
+    ("Queue", ANY, NEVER),
+    ("RUBYGEMS_ACTIVATION_MONITOR", ANY, NEVER),
+    ("RUBY_COPYRIGHT", ANY, NEVER),
+    ("RUBY_DESCRIPTION", ANY, NEVER),
+    ("RUBY_ENGINE", ANY, NEVER),
+    ("RUBY_ENGINE_VERSION", ANY, NEVER),
+    ("RUBY_PATCHLEVEL", ANY, NEVER),
+    ("RUBY_PLATFORM", ANY, NEVER),
+    ("RUBY_RELEASE_DATE", ANY, NEVER),
+    ("RUBY_REVISION", ANY, NEVER),
+    ("RUBY_VERSION", ANY, NEVER),
+    ("Ractor", (3, 0), NEVER),
+    ("Random", ANY, NEVER),
+    ("Range", ANY, NEVER),
+    ("RangeError", ANY, NEVER),
+    ("Rational", ANY, NEVER),
+    ("RbConfig", ANY, NEVER),
+    ("Refinement", (3, 1), NEVER),
+    ("Regexp", ANY, NEVER),
+    ("RegexpError", ANY, NEVER),
+    ("RubyVM", ANY, NEVER),
+    ("RuntimeError", ANY, NEVER),
+    ("STDERR", ANY, NEVER),
+    ("STDIN", ANY, NEVER),
+    ("STDOUT", ANY, NEVER),
+    ("ScriptError", ANY, NEVER),
+    ("SecurityError", ANY, NEVER),
+    ("Set", (3, 2), NEVER),
+    ("Signal", ANY, NEVER),
+    ("SignalException", ANY, NEVER),
+    ("SizedQueue", ANY, NEVER),
+    ("StandardError", ANY, NEVER),
+    ("StopIteration", ANY, NEVER),
+    ("String", ANY, NEVER),
+    ("Struct", ANY, NEVER),
+    ("Symbol", ANY, NEVER),
+    ("SyntaxError", ANY, NEVER),
+    ("SyntaxSuggest", (3, 2), NEVER),
+    ("SystemCallError", ANY, NEVER),
+    ("SystemExit", ANY, NEVER),
+    ("SystemStackError", ANY, NEVER),
+    ("TOPLEVEL_BINDING", ANY, NEVER),
+    ("Thread", ANY, NEVER),
+    ("ThreadError", ANY, NEVER),
+    ("ThreadGroup", ANY, NEVER),
+    ("Time", ANY, NEVER),
+    ("TracePoint", ANY, NEVER),
+    ("TrueClass", ANY, NEVER),
+    ("TypeError", ANY, NEVER),
+    ("UnboundMethod", ANY, NEVER),
+    ("UncaughtThrowError", ANY, NEVER),
+    ("UnicodeNormalize", ANY, NEVER),
+    ("Warning", ANY, NEVER),
+    ("ZeroDivisionError", ANY, NEVER),
 ];
+
+static STDLIB: &[Entry] = &[
+    ("Addrinfo", ANY, NEVER),
+    ("BasicSocket", ANY, NEVER),
+    ("Continuation", ANY, NEVER),
+    ("Coverage", ANY, NEVER),
+    ("English", ANY, NEVER),
+    ("IPSocket", ANY, NEVER),
+    ("Monitor", ANY, NEVER),
+    ("MonitorMixin", ANY, NEVER),
+    ("PTY", ANY, NEVER),
+    ("Ripper", ANY, NEVER),
+    ("Shellwords", ANY, NEVER),
+    ("Socket", ANY, NEVER),
+    ("SocketError", ANY, NEVER),
+    ("TCPServer", ANY, NEVER),
+    ("TCPSocket", ANY, NEVER),
+    ("UDPSocket", ANY, NEVER),
+    ("UNIXServer", ANY, NEVER),
+    ("UNIXSocket", ANY, NEVER),
+    ("TimeoutError", ANY, (3, 0)),
+    ("Un", ANY, NEVER),
+];
+
+static DEFAULT_GEMS: &[Entry] = &[
+    ("Abbrev", ANY, (3, 4)),
+    ("Base64", ANY, (3, 4)),
+    ("Benchmark", ANY, NEVER),
+    ("BigDecimal", ANY, (3, 4)),
+    ("CGI", ANY, NEVER),
+    ("CSV", ANY, (3, 4)),
+    ("DBM", ANY, (3, 0)),
+    ("DRb", ANY, (3, 4)),
+    ("Date", ANY, NEVER),
+    ("DateTime", ANY, NEVER),
+    ("DelegateClass", ANY, NEVER),
+    ("Delegator", ANY, NEVER),
+    ("Digest", ANY, NEVER),
+    ("ERB", ANY, NEVER),
+    ("Etc", ANY, NEVER),
+    ("Fcntl", ANY, NEVER),
+    ("Fiddle", ANY, NEVER),
+    ("FileUtils", ANY, NEVER),
+    ("Find", ANY, NEVER),
+    ("Forwardable", ANY, NEVER),
+    ("GDBM", ANY, (3, 1)),
+    ("GetoptLong", ANY, (3, 4)),
+    ("IPAddr", ANY, NEVER),
+    ("IRB", ANY, NEVER),
+    ("JSON", ANY, NEVER),
+    ("Kconv", ANY, (3, 4)),
+    ("Logger", ANY, NEVER),
+    ("Matrix", ANY, (3, 1)),
+    ("Mutex_m", ANY, (3, 4)),
+    ("NKF", ANY, (3, 4)),
+    ("Net", ANY, (3, 1)),
+    ("Net::HTTP", (3, 1), NEVER),
+    ("Net::HTTPResponse", (3, 1), NEVER),
+    ("Net::HTTPRequest", (3, 1), NEVER),
+    ("Net::HTTPExceptions", (3, 1), NEVER),
+    ("Net::OpenTimeout", (3, 1), NEVER),
+    ("Net::ReadTimeout", (3, 1), NEVER),
+    ("Net::WriteTimeout", (3, 1), NEVER),
+    ("Net::ProtocolError", (3, 1), NEVER),
+    ("Observable", ANY, (3, 4)),
+    ("Open3", ANY, NEVER),
+    ("OpenSSL", ANY, NEVER),
+    ("OpenStruct", ANY, NEVER),
+    ("OptionParser", ANY, NEVER),
+    ("PP", ANY, NEVER),
+    ("PStore", ANY, NEVER),
+    ("Pathname", ANY, NEVER),
+    ("PrettyPrint", ANY, NEVER),
+    ("Prime", ANY, (3, 1)),
+    ("Psych", ANY, NEVER),
+    ("RDoc", ANY, NEVER),
+    ("Racc", ANY, (3, 4)),
+    ("Readline", ANY, NEVER),
+    ("Reline", ANY, NEVER),
+    ("Resolv", ANY, NEVER),
+    ("Rinda", ANY, (3, 4)),
+    ("RubyLex", ANY, NEVER),
+    ("SDBM", ANY, (3, 0)),
+    ("SecureRandom", ANY, NEVER),
+    ("Set", ANY, (3, 2)),
+    ("SimpleDelegator", ANY, NEVER),
+    ("SingleForwardable", ANY, NEVER),
+    ("Singleton", ANY, NEVER),
+    ("SortedSet", ANY, (3, 0)),
+    ("StringIO", ANY, NEVER),
+    ("StringScanner", ANY, NEVER),
+    ("Syslog", ANY, (3, 4)),
+    ("TSort", ANY, NEVER),
+    ("Tempfile", ANY, NEVER),
+    ("Timeout", ANY, NEVER),
+    ("Tracer", ANY, (3, 0)),
+    ("URI", ANY, NEVER),
+    ("Vector", ANY, (3, 1)),
+    ("WEBrick", ANY, (3, 0)),
+    ("WeakRef", ANY, NEVER),
+    ("YAML", ANY, NEVER),
+    ("Zlib", ANY, NEVER),
+];
+
+#[derive(Debug)]
+pub struct UnknownRubyVersion(pub String);
+
+impl std::fmt::Display for UnknownRubyVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "unknown ruby version {}, expected one of {}",
+            self.0,
+            RUBY_VERSIONS.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownRubyVersion {}
+
+/// Constants that ship with Ruby and are never reported as box violations.
+///
+/// Matching is by `::` segment prefix: allowing `Encoding` also allows
+/// `Encoding::UTF_8`, but not `EncodingHelper`.
+#[derive(Default)]
+pub struct Builtins {
+    allowed: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl Builtins {
+    /// The constants of `tiers` for a Ruby `version` such as `3.1`, with
+    /// project specific roots added or removed.
+    pub fn new(
+        version: &str,
+        tiers: &[Tier],
+        add: &[String],
+        remove: &[String],
+    ) -> Result<Builtins, UnknownRubyVersion> {
+        if !RUBY_VERSIONS.contains(&version) {
+            return Err(UnknownRubyVersion(version.to_owned()));
+        }
+        let mut parts = version.split('.').map(|p| p.parse::<u32>().unwrap_or(0));
+        let version = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+
+        let mut allowed: Vec<String> = tiers
+            .iter()
+            .flat_map(|tier| match tier {
+                Tier::Core => CORE,
+                Tier::Stdlib => STDLIB,
+                Tier::DefaultGems => DEFAULT_GEMS,
+            })
+            .filter(|(_, since, until)| *since <= version && version < *until)
+            .map(|(name, _, _)| name.to_string())
+            .collect();
+        allowed.extend(add.iter().cloned());
+        Ok(Builtins {
+            allowed,
+            removed: remove.to_vec(),
+        })
+    }
+
+    pub fn contains(&self, constant: &str) -> bool {
+        !self.removed.iter().any(|root| covers(root, constant))
+            && self.allowed.iter().any(|root| covers(root, constant))
+    }
+}
+
+/// True when `constant` is `root` or nested somewhere below it.
+fn covers(root: &str, constant: &str) -> bool {
+    match constant.strip_prefix(root) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_nested_constants_by_segment() {
+        let builtins = Builtins::new(
+            "3.0",
+            ALL_TIERS,
+            &[String::from("Oj")],
+            &[String::from("JSON")],
+        )
+        .unwrap();
+        assert!(builtins.contains("String"));
+        assert!(builtins.contains("Encoding::UTF_8"));
+        assert!(builtins.contains("Oj::ParseError"));
+        assert!(!builtins.contains("EncodingHelper"));
+        assert!(!builtins.contains("JSON::ParserError"));
+    }
+
+    #[test]
+    fn selects_constants_by_version_and_tier() {
+        let core_32 = Builtins::new("3.2", &[Tier::Core], &[], &[]).unwrap();
+        assert!(core_32.contains("Data"));
+        assert!(core_32.contains("Set"));
+        assert!(!core_32.contains("Fixnum"));
+        assert!(!core_32.contains("JSON"));
+
+        let all_27 = Builtins::new("2.7", ALL_TIERS, &[], &[]).unwrap();
+        assert!(!all_27.contains("Ractor"));
+        assert!(all_27.contains("Net::SMTP"));
+        let all_31 = Builtins::new("3.1", ALL_TIERS, &[], &[]).unwrap();
+        assert!(!all_31.contains("Net::SMTP"));
+        assert!(all_31.contains("Net::HTTP::Get"));
+
+        assert!(Builtins::new("1.9", ALL_TIERS, &[], &[]).is_err());
+    }
+}
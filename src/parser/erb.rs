@@ -0,0 +1,76 @@
+/// Turns an ERB template into Ruby source that keeps every character of the
+/// template on the same line and column.
+///
+/// Template text, tag delimiters and `<%# %>` comments are blanked out with
+/// spaces (newlines are kept) and each closing `%>` becomes `;` so consecutive
+/// tags stay separate statements. Constants found in the result therefore point
+/// straight back into the template.
+pub fn to_ruby(template: &[u8]) -> Vec<u8> {
+    let template = String::from_utf8_lossy(template);
+    let chars: Vec<char> = template.chars().collect();
+    let mut ruby = String::with_capacity(chars.len());
+    let mut i = 0;
+
+    let blank = |ruby: &mut String, c: char| ruby.push(if c == '\n' { '\n' } else { ' ' });
+
+    while i < chars.len() {
+        if chars[i] != '<' || chars.get(i + 1) != Some(&'%') {
+            blank(&mut ruby, chars[i]);
+            i += 1;
+            continue;
+        }
+        // `<%%` is a literal `<%` in the output
+        if chars.get(i + 2) == Some(&'%') {
+            ruby.push_str("   ");
+            i += 3;
+            continue;
+        }
+
+        ruby.push_str("  ");
+        i += 2;
+        let comment = chars.get(i) == Some(&'#');
+        while i < chars.len() && (chars[i] == '=' || chars[i] == '-' || chars[i] == '#') {
+            ruby.push(' ');
+            i += 1;
+            if comment {
+                break;
+            }
+        }
+
+        while i < chars.len() {
+            if chars[i] == '%' && chars.get(i + 1) == Some(&'>') {
+                ruby.push_str("; ");
+                i += 2;
+                break;
+            }
+            let trim_close =
+                chars[i] == '-' && chars.get(i + 1) == Some(&'%') && chars.get(i + 2) == Some(&'>');
+            if comment || trim_close {
+                blank(&mut ruby, chars[i]);
+            } else {
+                ruby.push(chars[i]);
+            }
+            i += 1;
+        }
+    }
+
+    ruby.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_code_in_place() {
+        let template = "<h1><%= Billing::Invoice.title %></h1>\n<%# Hidden::Comment %>\n<% if x -%>\n  <%% literal %>\n<% end %>\n";
+        let ruby = String::from_utf8(to_ruby(template.as_bytes())).unwrap();
+        assert_eq!(
+            ruby,
+            "        Billing::Invoice.title ;      \n                    ; \n   if x  ; \n                \n   end ; \n"
+        );
+        for (template_line, ruby_line) in template.lines().zip(ruby.lines()) {
+            assert_eq!(template_line.chars().count(), ruby_line.chars().count());
+        }
+    }
+}
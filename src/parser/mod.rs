@@ -1,125 +1,580 @@
 use crossbeam_channel::{Receiver, Sender};
+use lib_ruby_parser::source::buffer::Input;
+use lib_ruby_parser::source::CommentType;
 use lib_ruby_parser::traverse::Visitor;
-use lib_ruby_parser::{Node, Parser, ParserOptions, ParserResult};
-use std::cell::Cell;
+use lib_ruby_parser::{ErrorLevel, Node, Parser, ParserOptions, ParserResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Read;
 use std::option::Option;
 use std::path;
 mod constants;
+pub use constants::{Builtins, Tier, ALL_TIERS, DEFAULT_RUBY_VERSION};
+mod erb;
+mod rails;
+mod suppressions;
+pub use suppressions::{suppress, suppression_problems, Suppression, UsedSuppressions};
+
+/// Start and end of a piece of source. Lines and columns are 1-based and
+/// columns count characters, as editors expect in `file:line:col`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Default)]
+pub struct Span {
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Span {
+    fn new(range: &lib_ruby_parser::source::Range, input: &Input) -> Span {
+        let (start_line, start_column) = line_col(range.begin_pos, input);
+        let (end_line, end_column) = line_col(range.end_pos, input);
+        Span {
+            start_line,
+            start_column,
+            end_line,
+            end_column,
+        }
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.start_line, self.start_column)
+    }
+}
+
+fn line_col(pos: usize, input: &Input) -> (usize, usize) {
+    match input.line_col_for_pos(pos) {
+        Some((line, byte_column)) => {
+            let start = input.lines[line].start;
+            let column = String::from_utf8_lossy(&input.bytes[start..start + byte_column])
+                .chars()
+                .count();
+            (line + 1, column + 1)
+        }
+        None => (0, 0),
+    }
+}
 
 pub struct Definition {
     pub namespace: String,
     pub file: path::PathBuf,
-    pub line: usize,
-    pub lines: usize,
+    pub span: Span,
 }
 
 #[derive(PartialEq, Clone)]
 pub struct Relation {
     pub namespace: String,
+    /// Fully qualified constant the reference points at, filled in by `resolve`.
+    pub resolved: String,
+    pub file: path::PathBuf,
+    pub span: Span,
+    /// Lexical scopes the reference was made from, innermost first, as
+    /// `Module.nesting` would report them. Empty for `::Foo` references.
+    pub nesting: Vec<String>,
+    pub kind: RelationKind,
+    pub origin: Origin,
+    /// Gem providing the constant, when it is not defined in the codebase.
+    pub gem: Option<String>,
+    /// Span of the comment in the same file that suppresses the reference,
+    /// filled in by `suppress`.
+    pub suppressed_by: Option<Span>,
+}
+
+/// Where in the source a relation was found.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Origin {
+    /// A constant written in the code.
+    Constant,
+    /// A constant named by a string or symbol, e.g. `"Billing::Invoice".constantize`.
+    Dynamic,
+    /// A constant implied by a Rails macro, e.g. `has_many :orders`.
+    Inferred,
+}
+
+/// Settings that change what the parser extracts from each file.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Infer constants from Rails association macros like `has_many :orders`.
+    pub rails: bool,
+}
+
+/// Files treated as Ruby when none are configured: Ruby sources, ERB
+/// templates, rake tasks, gemspecs, rackup files and Thor scripts.
+pub static DEFAULT_PATTERNS: &[&str] = &[
+    "**/*.rb",
+    "**/*.erb",
+    "**/*.rake",
+    "**/*.gemspec",
+    "**/*.ru",
+    "**/*.thor",
+];
+
+/// Extensionless files treated as Ruby when none are configured.
+pub static DEFAULT_FILENAMES: &[&str] = &[
+    "Rakefile",
+    "Gemfile",
+    "Guardfile",
+    "Capfile",
+    "Thorfile",
+    "Vagrantfile",
+];
+
+/// Which files of the codebase are analyzed as Ruby.
+pub struct Sources {
+    /// Globs relative to the current directory, e.g. `**/*.rake`.
+    pub patterns: Vec<String>,
+    /// File names matched in any directory, e.g. `Rakefile`.
+    pub filenames: Vec<String>,
+    /// Globs of files left out even when they match, e.g. `vendor/**`.
+    pub exclude: Vec<String>,
+}
+
+impl Default for Sources {
+    fn default() -> Sources {
+        Sources {
+            patterns: DEFAULT_PATTERNS.iter().map(|p| p.to_string()).collect(),
+            filenames: DEFAULT_FILENAMES.iter().map(|f| f.to_string()).collect(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl Sources {
+    /// Every matching file, listed once, along with the directories that
+    /// could not be searched. Anything matched that is not a directory is
+    /// listed, so files that turn out to be unreadable are reported.
+    pub fn files(&self) -> Result<(Vec<path::PathBuf>, Vec<FileError>), glob::PatternError> {
+        self.files_in(path::Path::new(""))
+    }
+
+    /// Like `files`, searching `dir` instead of the current directory.
+    fn files_in(
+        &self,
+        dir: &path::Path,
+    ) -> Result<(Vec<path::PathBuf>, Vec<FileError>), glob::PatternError> {
+        let root = glob::Pattern::escape(&dir.to_string_lossy());
+        let under = |pattern: &str| match root.as_str() {
+            "" => pattern.to_owned(),
+            root => format!("{}/{}", root, pattern),
+        };
+        let mut globs = Vec::new();
+        for pattern in &self.patterns {
+            globs.push(glob::glob(&under(pattern))?);
+        }
+        for filename in &self.filenames {
+            let pattern = format!("**/{}", glob::Pattern::escape(filename));
+            globs.push(glob::glob(&under(&pattern))?);
+        }
+        let mut exclude = Vec::new();
+        for pattern in &self.exclude {
+            exclude.push(glob::Pattern::new(pattern)?);
+        }
+
+        let mut seen = HashSet::new();
+        let mut files = Vec::new();
+        let mut errors = Vec::new();
+        for entry in globs.into_iter().flatten() {
+            match entry {
+                Ok(path) => {
+                    let relative = path.strip_prefix(dir).unwrap_or(&path);
+                    if exclude.iter().any(|p| p.matches_path(relative)) {
+                        continue;
+                    }
+                    if !path.is_dir() && seen.insert(path.clone()) {
+                        files.push(path);
+                    }
+                }
+                Err(e) => errors.push(FileError {
+                    file: e.path().to_path_buf(),
+                    message: e.error().to_string(),
+                }),
+            }
+        }
+        Ok((files, errors))
+    }
+}
+
+/// How a constant is used at the place it is referenced.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelationKind {
+    /// Any other use, such as `Billing::Invoice.new`.
+    Reference,
+    /// Superclass of a `class` definition.
+    Inheritance,
+    /// Argument to `include`, `extend` or `prepend`.
+    Mixin,
+    /// Matched against in `rescue`, `case/when` or `is_a?` style checks.
+    TypeCheck,
+}
+
+impl std::fmt::Display for RelationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelationKind::Reference => write!(f, "reference"),
+            RelationKind::Inheritance => write!(f, "inheritance"),
+            RelationKind::Mixin => write!(f, "mixin"),
+            RelationKind::TypeCheck => write!(f, "type check"),
+        }
+    }
+}
+
+/// A warning or error the Ruby parser reported for a file.
+pub struct Diagnostic {
+    pub file: path::PathBuf,
+    pub span: Span,
+    pub message: String,
+    pub error: bool,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.file.to_str().unwrap(),
+            self.span,
+            if self.error { "error" } else { "warning" },
+            self.message
+        )
+    }
+}
+
+/// A file that could not be analyzed at all, e.g. because it could not be read.
+pub struct FileError {
     pub file: path::PathBuf,
-    pub line: usize,
-    pub caller_namespace: String,
+    pub message: String,
 }
 
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.file.to_string_lossy(), self.message)
+    }
+}
+
+#[derive(Default)]
 pub struct RubyFile {
     pub definitions: Vec<Definition>,
     pub relations: Vec<Relation>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub suppressions: Vec<Suppression>,
+}
+
+/// Everything extracted from the Ruby files of a codebase.
+#[derive(Default)]
+pub struct Codebase {
+    pub definitions: Vec<Definition>,
+    pub relations: Vec<Relation>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub suppressions: Vec<Suppression>,
+    pub errors: Vec<FileError>,
+}
+
+impl Codebase {
+    pub fn add(&mut self, result: Result<RubyFile, FileError>) {
+        match result {
+            Ok(mut ruby_file) => {
+                self.definitions.append(&mut ruby_file.definitions);
+                self.relations.append(&mut ruby_file.relations);
+                self.diagnostics.append(&mut ruby_file.diagnostics);
+                self.suppressions.append(&mut ruby_file.suppressions);
+            }
+            Err(error) => self.errors.push(error),
+        }
+    }
+
+    /// Drops references to constants that come with Ruby.
+    pub fn remove_builtins(&mut self, builtins: &Builtins) {
+        self.relations.retain(|r| !builtins.contains(&r.resolved));
+    }
+
+    /// Moves the constants found in files matching `predicate` into a
+    /// codebase of their own.
+    pub fn split_off<P: Fn(&path::Path) -> bool>(&mut self, predicate: P) -> Codebase {
+        let mut split = Codebase::default();
+        let (taken, kept) = self.definitions.drain(..).partition(|d| predicate(&d.file));
+        split.definitions = taken;
+        self.definitions = kept;
+        let (taken, kept) = self.relations.drain(..).partition(|r| predicate(&r.file));
+        split.relations = taken;
+        self.relations = kept;
+        let (taken, kept) = self.diagnostics.drain(..).partition(|d| predicate(&d.file));
+        split.diagnostics = taken;
+        self.diagnostics = kept;
+        let (taken, kept) = self
+            .suppressions
+            .drain(..)
+            .partition(|s| predicate(&s.file));
+        split.suppressions = taken;
+        self.suppressions = kept;
+        split
+    }
+
+    /// Files the parser could not make sense of. Their constants are missing
+    /// from the analysis.
+    pub fn unparsable_files(&self) -> HashSet<&path::Path> {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.error)
+            .map(|d| d.file.as_path())
+            .collect()
+    }
 }
 
 struct ExtractConsts<'a> {
     file: path::PathBuf,
-    parents: Vec<String>,
+    /// Fully qualified names of the modules and classes currently open, outermost first.
+    nesting: Vec<String>,
+    /// Kind given to the constant currently being visited.
+    kind: RelationKind,
     ruby_file: RubyFile,
     parser_result: &'a ParserResult,
+    options: &'a Options,
+}
+
+impl<'a> ExtractConsts<'a> {
+    fn push_relation(
+        &mut self,
+        namespace: String,
+        nesting: Vec<String>,
+        range: &lib_ruby_parser::source::Range,
+        origin: Origin,
+    ) {
+        let rel = Relation {
+            resolved: namespace.clone(),
+            namespace,
+            nesting,
+            kind: self.kind,
+            origin,
+            gem: None,
+            suppressed_by: None,
+            file: self.file.clone(),
+            span: self.span(range),
+        };
+        self.ruby_file.relations.push(rel);
+    }
+
+    fn span(&self, range: &lib_ruby_parser::source::Range) -> Span {
+        Span::new(range, &self.parser_result.input)
+    }
+
+    /// Lexical scopes for a reference made here, innermost first.
+    fn lexical_nesting(&self) -> Vec<String> {
+        self.nesting.iter().rev().cloned().collect()
+    }
+
+    /// Records constants hidden in strings and symbols passed to
+    /// `constantize`, `safe_constantize`, `const_get` and `class_name:`.
+    fn visit_dynamic(&mut self, node: &lib_ruby_parser::nodes::Send) {
+        match (node.method_name.as_str(), node.recv.as_deref()) {
+            ("constantize", Some(recv)) | ("safe_constantize", Some(recv)) => {
+                // constantize always resolves from the top level
+                if let Some((name, _)) = literal_const_name(recv) {
+                    self.push_relation(name, Vec::new(), recv.expression(), Origin::Dynamic);
+                }
+            }
+            ("const_get", recv) => {
+                let arg = match node.args.first() {
+                    Some(arg) => arg,
+                    None => return,
+                };
+                let (name, absolute) = match literal_const_name(arg) {
+                    Some(name) => name,
+                    None => return,
+                };
+                let (name, nesting) = match recv {
+                    _ if absolute => (name, Vec::new()),
+                    None | Some(Node::Self_(_)) => {
+                        (name, self.nesting.last().cloned().into_iter().collect())
+                    }
+                    Some(Node::Const(c)) => match const_path(&c.scope, &c.name) {
+                        Ok((scope, _)) if scope == "Object" => (name, Vec::new()),
+                        Ok((scope, true)) => (format!("{}::{}", scope, name), Vec::new()),
+                        Ok((scope, false)) => {
+                            (format!("{}::{}", scope, name), self.lexical_nesting())
+                        }
+                        Err(_) => return,
+                    },
+                    Some(_) => return,
+                };
+                self.push_relation(name, nesting, arg.expression(), Origin::Dynamic);
+            }
+            _ => {}
+        }
+
+        if let Some(value) = keyword_arg(&node.args, "class_name") {
+            if let Some((name, absolute)) = literal_const_name(value) {
+                let nesting = if absolute {
+                    Vec::new()
+                } else {
+                    self.module_parents()
+                };
+                self.push_relation(name, nesting, value.expression(), Origin::Dynamic);
+            }
+        }
+    }
+
+    /// Records the model behind `belongs_to`, `has_one`, `has_many` and
+    /// `has_and_belongs_to_many` associations that do not name a `class_name:`.
+    fn visit_association(&mut self, node: &lib_ruby_parser::nodes::Send) {
+        if node.recv.is_some()
+            || keyword_arg(&node.args, "class_name").is_some()
+            || matches!(keyword_arg(&node.args, "polymorphic"), Some(Node::True(_)))
+        {
+            return;
+        }
+        let (association, range) = match node.args.first() {
+            Some(Node::Sym(sym)) => match sym.name.to_string() {
+                Some(name) => (name, &sym.expression_l),
+                None => return,
+            },
+            _ => return,
+        };
+        if let Some(class) = rails::association_class(&node.method_name, &association) {
+            let nesting = self.module_parents();
+            self.push_relation(class, nesting, range, Origin::Inferred);
+        }
+    }
+
+    /// The class being defined followed by each of its namespaces, the scopes
+    /// Rails searches when turning `class_name:` into a constant.
+    fn module_parents(&self) -> Vec<String> {
+        let mut parents = Vec::new();
+        if let Some(current) = self.nesting.last() {
+            let mut parts: Vec<&str> = current.split("::").collect();
+            while !parts.is_empty() {
+                parents.push(parts.join("::"));
+                parts.pop();
+            }
+        }
+        parents
+    }
+
+    /// Visits `node`, tagging it with `kind` when it is a constant itself.
+    /// Constants nested deeper, e.g. arguments of a call, stay plain references.
+    fn visit_as(&mut self, kind: RelationKind, node: &Node) {
+        match node {
+            Node::Const(_) => {
+                self.kind = kind;
+                self.visit(node);
+                self.kind = RelationKind::Reference;
+            }
+            Node::Array(array) => {
+                for element in &array.elements {
+                    self.visit_as(kind, element);
+                }
+            }
+            _ => {
+                self.visit(node);
+            }
+        }
+    }
 }
 
 impl<'a> lib_ruby_parser::traverse::Visitor<Option<Node>> for ExtractConsts<'a> {
     fn on_module(&mut self, node: &lib_ruby_parser::nodes::Module) -> Option<Node> {
-        let ns = namespace(node.name.as_ref().clone(), &mut self.parents.clone()).unwrap();
+        let ns = match namespace(&node.name, &self.nesting) {
+            Ok(ns) => ns,
+            Err(_) => return self.maybe_visit(&node.body),
+        };
         let def = Definition {
-            namespace: ns,
+            namespace: ns.clone(),
             file: self.file.clone(),
-            line: node.keyword_l.begin_pos,
-            lines: node.end_l.end_pos,
+            span: self.span(&node.expression_l),
         };
         self.ruby_file.definitions.push(def);
 
-        let ns = extract_name(node.name.as_ref().clone()).unwrap();
-        self.parents.push(ns);
+        self.nesting.push(ns);
         let resp = self.maybe_visit(&node.body);
-        self.parents.pop();
+        self.nesting.pop();
         resp
     }
 
     fn on_class(&mut self, node: &lib_ruby_parser::nodes::Class) -> Option<Node> {
-        let ns = namespace(node.name.as_ref().clone(), &mut self.parents.clone()).unwrap();
+        if let Some(superclass) = &node.superclass {
+            self.visit_as(RelationKind::Inheritance, superclass);
+        }
+        let ns = match namespace(&node.name, &self.nesting) {
+            Ok(ns) => ns,
+            Err(_) => return self.maybe_visit(&node.body),
+        };
         let def = Definition {
-            namespace: ns,
+            namespace: ns.clone(),
             file: self.file.clone(),
-            line: node.keyword_l.begin_pos,
-            lines: node.end_l.end_pos,
+            span: self.span(&node.expression_l),
         };
 
         self.ruby_file.definitions.push(def);
 
-        let ns = extract_name(node.name.as_ref().clone()).unwrap();
-        self.parents.push(ns);
-        self.maybe_visit(&node.superclass);
+        self.nesting.push(ns);
         let resp = self.maybe_visit(&node.body);
-        self.parents.pop();
+        self.nesting.pop();
         resp
     }
 
     fn on_casgn(&mut self, node: &lib_ruby_parser::nodes::Casgn) -> Option<Node> {
-        let mut ns = self.parents.clone();
-        let scope = Cell::new(node.scope.clone());
-        while let Some(b) = scope.take() {
-            if let Node::Const(n) = *b {
-                ns.push(n.name);
-                scope.set(n.scope);
-            }
-        }
-        ns.push(node.name.clone());
+        let (name, absolute) =
+            const_path(&node.scope, &node.name).unwrap_or_else(|_| (node.name.clone(), false));
+        let ns = match self.nesting.last() {
+            Some(parent) if !absolute => format!("{}::{}", parent, name),
+            _ => name,
+        };
         let def = Definition {
-            namespace: ns.join("::"),
+            namespace: ns,
             file: self.file.clone(),
-            line: node.name_l.begin_pos,
-            lines: node.name_l.size(),
+            span: self.span(&node.expression_l),
         };
 
         self.ruby_file.definitions.push(def);
+        self.maybe_visit(&node.value);
 
         None
     }
 
-    fn on_const(&mut self, node: &lib_ruby_parser::nodes::Const) -> Option<Node> {
-        let mut ns = Vec::new();
-        let scope = Cell::new(node.scope.clone());
-        while let Some(b) = scope.take() {
-            if let Node::Const(n) = *b {
-                ns.push(n.name);
-                scope.set(n.scope);
-            }
+    fn on_send(&mut self, node: &lib_ruby_parser::nodes::Send) -> Option<Node> {
+        self.visit_dynamic(node);
+        if self.options.rails {
+            self.visit_association(node);
         }
-        ns.reverse();
-        ns.push(node.name.clone());
-        let full_ns = ns.join("::");
-        if constants::RUBY.contains(&full_ns.as_str()) {
-            return None;
+        self.maybe_visit(&node.recv);
+        let kind = match node.method_name.as_str() {
+            "include" | "extend" | "prepend" if node.recv.is_none() => RelationKind::Mixin,
+            "is_a?" | "kind_of?" | "instance_of?" => RelationKind::TypeCheck,
+            _ => RelationKind::Reference,
+        };
+        for arg in &node.args {
+            self.visit_as(kind, arg);
         }
-        let (line, _) = node
-            .expression_l
-            .expand_to_line(&self.parser_result.input)
-            .unwrap();
-        let rel = Relation {
-            namespace: full_ns,
-            caller_namespace: self.parents.join("::"),
-            file: self.file.clone(),
-            line: line + 1,
+        None
+    }
+
+    fn on_rescue_body(&mut self, node: &lib_ruby_parser::nodes::RescueBody) -> Option<Node> {
+        if let Some(exc_list) = &node.exc_list {
+            self.visit_as(RelationKind::TypeCheck, exc_list);
+        }
+        self.maybe_visit(&node.exc_var);
+        self.maybe_visit(&node.body)
+    }
+
+    fn on_when(&mut self, node: &lib_ruby_parser::nodes::When) -> Option<Node> {
+        for pattern in &node.patterns {
+            self.visit_as(RelationKind::TypeCheck, pattern);
+        }
+        self.maybe_visit(&node.body)
+    }
+
+    fn on_const(&mut self, node: &lib_ruby_parser::nodes::Const) -> Option<Node> {
+        let (full_ns, absolute) =
+            const_path(&node.scope, &node.name).unwrap_or_else(|_| (node.name.clone(), false));
+        let nesting = if absolute {
+            Vec::new()
+        } else {
+            self.lexical_nesting()
         };
-        self.ruby_file.relations.push(rel);
+        self.push_relation(full_ns, nesting, &node.expression_l, Origin::Constant);
 
         None
     }
@@ -140,77 +595,570 @@ impl std::fmt::Display for NotAConstError {
 
 impl std::error::Error for NotAConstError {}
 
-fn namespace(name: Node, parents: &mut Vec<String>) -> Result<String, Box<dyn std::error::Error>> {
-    let n = extract_name(name)?;
-    parents.push(n);
-    Ok(parents.join("::"))
+/// Fully qualified name of a `module`/`class` definition opened inside `nesting`.
+/// Compact names such as `class Foo::Bar` are appended as a whole and `::Foo`
+/// ignores the enclosing scopes.
+fn namespace(name: &Node, nesting: &[String]) -> Result<String, Box<dyn std::error::Error>> {
+    let (n, absolute) = extract_name(name)?;
+    match nesting.last() {
+        Some(parent) if !absolute => Ok(format!("{}::{}", parent, n)),
+        _ => Ok(n),
+    }
 }
 
-fn extract_name(name: Node) -> Result<String, Box<dyn std::error::Error>> {
+fn extract_name(name: &Node) -> Result<(String, bool), Box<dyn std::error::Error>> {
     match name {
-        Node::Const(c) => Ok(c.name),
-        _ => Err(Box::new(NotAConstError(name))),
+        Node::Const(c) => const_path(&c.scope, &c.name),
+        _ => Err(Box::new(NotAConstError(name.clone()))),
     }
 }
 
-fn ruby_file(path: path::PathBuf, contents: &[u8]) -> Result<RubyFile, Box<dyn std::error::Error>> {
-    let options = ParserOptions {
+/// Joins a constant and its `::` scope chain into a path, flagging paths
+/// anchored at the top level with a leading `::`.
+fn const_path(
+    scope: &Option<Box<Node>>,
+    name: &str,
+) -> Result<(String, bool), Box<dyn std::error::Error>> {
+    match scope.as_deref() {
+        None => Ok((name.to_owned(), false)),
+        Some(Node::Cbase(_)) => Ok((name.to_owned(), true)),
+        Some(parent) => {
+            let (path, absolute) = extract_name(parent)?;
+            Ok((format!("{}::{}", path, name), absolute))
+        }
+    }
+}
+
+/// Value of the `key:` option in a method call's trailing hash, if given.
+fn keyword_arg<'n>(args: &'n [Node], key: &str) -> Option<&'n Node> {
+    let pairs = match args.last()? {
+        Node::Hash(hash) => &hash.pairs,
+        Node::Kwargs(kwargs) => &kwargs.pairs,
+        _ => return None,
+    };
+    pairs.iter().find_map(|pair| match pair {
+        Node::Pair(pair) => match pair.key.as_ref() {
+            Node::Sym(sym) if sym.name.as_bytes() == key.as_bytes() => Some(pair.value.as_ref()),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// Whether `segment` is a single constant name such as `Invoice`.
+pub fn is_constant_name(segment: &str) -> bool {
+    segment.starts_with(|c: char| c.is_ascii_uppercase())
+        && segment.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Reads a string or symbol literal that names a constant, e.g. `"::Billing::Invoice"`,
+/// flagging names anchored at the top level.
+fn literal_const_name(node: &Node) -> Option<(String, bool)> {
+    let value = match node {
+        Node::Str(s) => s.value.to_string()?,
+        Node::Sym(s) => s.name.to_string()?,
+        _ => return None,
+    };
+    let (name, absolute) = match value.strip_prefix("::") {
+        Some(name) => (name, true),
+        None => (value.as_str(), false),
+    };
+    if name.split("::").all(is_constant_name) {
+        Some((name.to_owned(), absolute))
+    } else {
+        None
+    }
+}
+
+fn ruby_file(
+    path: path::PathBuf,
+    contents: &[u8],
+    options: &Options,
+) -> Result<RubyFile, Box<dyn std::error::Error>> {
+    let parser_options = ParserOptions {
         buffer_name: "(eval)".to_owned(),
         debug: false,
         ..Default::default()
     };
-    let parser = Parser::new(&contents, options);
+    let parser = Parser::new(contents, parser_options);
     let result = parser.do_parse();
-    let ruby_file = RubyFile {
-        definitions: Vec::new(),
-        relations: Vec::new(),
-    };
+    let mut diagnostics: Vec<Diagnostic> = result
+        .diagnostics
+        .iter()
+        .map(|d| Diagnostic {
+            file: path.clone(),
+            span: Span::new(&d.range, &result.input),
+            message: d.render_message(),
+            error: d.level == ErrorLevel::Error,
+        })
+        .collect();
+    let comments: Vec<(Span, String)> = result
+        .comments
+        .iter()
+        .filter(|c| c.kind == CommentType::Inline)
+        .filter_map(|c| {
+            let text = c.location.source(&result.input)?;
+            // inline comments end after their newline
+            let text = text.trim_end_matches(&['\r', '\n'][..]);
+            let location = c.location.with_end(c.location.begin_pos + text.len());
+            Some((Span::new(&location, &result.input), text.to_owned()))
+        })
+        .collect();
+    let (suppressions, mut directive_warnings) =
+        suppressions::extract(&path, &comments, result.input.lines.len());
+    diagnostics.append(&mut directive_warnings);
     let mut visitor = ExtractConsts {
-        parents: Vec::new(),
-        file: path.clone(),
-        ruby_file: ruby_file,
+        nesting: Vec::new(),
+        kind: RelationKind::Reference,
+        file: path,
+        ruby_file: RubyFile {
+            diagnostics,
+            suppressions,
+            ..Default::default()
+        },
         parser_result: &result,
+        options,
     };
 
-    match &result.ast {
-        Some(n) => visitor.visit(&n),
-        None => {
-            return Ok(RubyFile {
-                definitions: Vec::new(),
-                relations: Vec::new(),
-            })
-        }
-    };
+    if let Some(n) = &result.ast {
+        visitor.visit(n);
+    }
 
-    let mut defs = Vec::new();
-    let mut rels = Vec::new();
+    Ok(visitor.ruby_file)
+}
 
-    for def in visitor.ruby_file.definitions.drain(0..) {
-        defs.push(def);
+/// Points every relation at the fully qualified constant Ruby would find for it.
+///
+/// Follows `Module.nesting` semantics: the first segment of the reference is
+/// looked up in each enclosing scope, innermost first, against the constants
+/// defined anywhere in the codebase, before falling back to the top level.
+pub fn resolve(defs: &[Definition], rels: &mut [Relation]) {
+    let known: HashSet<&str> = defs.iter().map(|d| d.namespace.as_str()).collect();
+    for rel in rels.iter_mut() {
+        rel.resolved = resolve_name(&known, &rel.nesting, &rel.namespace);
     }
+}
 
-    for rel in visitor.ruby_file.relations.drain(0..) {
-        rels.push(rel);
+fn resolve_name(known: &HashSet<&str>, nesting: &[String], name: &str) -> String {
+    let root = name.split("::").next().unwrap_or(name);
+    for scope in nesting {
+        if known.contains(format!("{}::{}", scope, root).as_str()) {
+            return format!("{}::{}", scope, name);
+        }
     }
-
-    Ok(RubyFile {
-        definitions: defs,
-        relations: rels,
-    })
+    name.to_owned()
 }
 
+/// Parses every path received on `rx`. A file that cannot be read or
+/// analyzed is sent back as a `FileError` and the worker moves on.
 pub fn worker(
     rx: Receiver<path::PathBuf>,
-    tx: Sender<RubyFile>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    tx: Sender<Result<RubyFile, FileError>>,
+    options: Options,
+) {
     for path in rx.iter() {
-        let mut file = File::open(&path)?;
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)?;
+        let result = analyze(&path, &options).map_err(|message| FileError {
+            file: path.clone(),
+            message,
+        });
+        if tx.send(result).is_err() {
+            break;
+        }
+    }
+}
+
+thread_local! {
+    /// Whether this thread is inside the parser, whose panics become errors.
+    static PARSING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
 
-        let rf = ruby_file(path, &contents)?;
-        tx.send(rf)?;
+/// Keeps panics of the parser from being printed, as the file they happen in
+/// is reported as an error instead. Other panics print as usual.
+fn quiet_parser_panics() {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        let default = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !PARSING.with(|parsing| parsing.get()) {
+                default(info);
+            }
+        }));
+    });
+}
+
+fn analyze(path: &path::Path, options: &Options) -> Result<RubyFile, String> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .map_err(|e| e.to_string())?;
+
+    if path.extension() == Some(std::ffi::OsStr::new("erb")) {
+        contents = erb::to_ruby(&contents);
+    }
+
+    quiet_parser_panics();
+    PARSING.with(|parsing| parsing.set(true));
+    let parsed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        ruby_file(path.to_path_buf(), &contents, options)
+    }));
+    PARSING.with(|parsing| parsing.set(false));
+    match parsed {
+        Ok(Ok(rf)) => Ok(rf),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(String::from("the parser panicked on this file")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> RubyFile {
+        parse_with(source, &Options::default())
+    }
+
+    fn parse_with(source: &str, options: &Options) -> RubyFile {
+        let mut rf = ruby_file(path::PathBuf::from("test.rb"), source.as_bytes(), options).unwrap();
+        resolve(&rf.definitions, &mut rf.relations);
+        rf
+    }
+
+    fn resolved(rf: &RubyFile) -> Vec<&str> {
+        rf.relations.iter().map(|r| r.resolved.as_str()).collect()
+    }
+
+    #[test]
+    fn resolves_relative_references_through_lexical_scope() {
+        let rf = parse(
+            "module A
+              class Error; end
+              module B
+                class C
+                  def call
+                    raise Error
+                  end
+                end
+              end
+            end",
+        );
+        assert_eq!(resolved(&rf), vec!["A::Error"]);
+    }
+
+    #[test]
+    fn falls_back_to_top_level_references() {
+        let rf = parse(
+            "module A
+              module B
+                def call
+                  Other::Thing
+                  ::B
+                end
+              end
+            end",
+        );
+        assert_eq!(resolved(&rf), vec!["Other::Thing", "B"]);
+    }
+
+    #[test]
+    fn compact_and_nested_definitions_share_names() {
+        let nested = parse("module Admin; module Users; class Exporter; end; end; end");
+        let compact = parse(
+            "module Admin
+              class Users::Exporter; end
+            end
+            class Admin::Users::Importer; end
+            module ::Top; end",
+        );
+        let names = |rf: &RubyFile| -> Vec<String> {
+            rf.definitions.iter().map(|d| d.namespace.clone()).collect()
+        };
+        assert_eq!(
+            names(&nested),
+            vec!["Admin", "Admin::Users", "Admin::Users::Exporter"]
+        );
+        assert_eq!(
+            names(&compact),
+            vec![
+                "Admin",
+                "Admin::Users::Exporter",
+                "Admin::Users::Importer",
+                "Top"
+            ]
+        );
+    }
+
+    #[test]
+    fn compact_bodies_do_not_see_outer_modules() {
+        let rf = parse(
+            "module Admin
+              class Error; end
+            end
+            class Admin::Users::Exporter
+              def call
+                raise Error
+              end
+            end",
+        );
+        assert_eq!(resolved(&rf), vec!["Error"]);
+    }
+
+    #[test]
+    fn dynamic_class_names_do_not_panic() {
+        let rf = parse("class factory::Widget; Other; end");
+        assert!(rf.definitions.is_empty());
+        assert_eq!(resolved(&rf), vec!["Other"]);
+    }
+
+    #[test]
+    fn classifies_relation_kinds() {
+        let rf = parse(
+            "class Invoice < Billing::Base
+              include Billing::Concern
+              extend Billing::ClassMethods
+
+              def call(x)
+                Billing::Invoice.new(Billing::Arg)
+                x.is_a?(Billing::Checked)
+                case x
+                when Billing::Matched then nil
+                end
+              rescue Billing::Error, Billing::OtherError
+              end
+            end",
+        );
+        let kinds: Vec<(&str, RelationKind)> = rf
+            .relations
+            .iter()
+            .map(|r| (r.namespace.as_str(), r.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("Billing::Base", RelationKind::Inheritance),
+                ("Billing::Concern", RelationKind::Mixin),
+                ("Billing::ClassMethods", RelationKind::Mixin),
+                ("Billing::Invoice", RelationKind::Reference),
+                ("Billing::Arg", RelationKind::Reference),
+                ("Billing::Checked", RelationKind::TypeCheck),
+                ("Billing::Matched", RelationKind::TypeCheck),
+                ("Billing::Error", RelationKind::TypeCheck),
+                ("Billing::OtherError", RelationKind::TypeCheck),
+            ]
+        );
+    }
+
+    #[test]
+    fn records_string_based_references_as_dynamic() {
+        let rf = parse(
+            "module Billing
+              class Invoice
+                has_many :items, class_name: \"LineItem\"
+                LEDGER = \"Reports::Ledger\".constantize
+                KLASS = Object.const_get(:Account)
+                DEFAULT = Reports::Export
+                def call
+                  \"Billing::Ledger\".constantize
+                  \"Reports::Export\".safe_constantize
+                  Object.const_get(:Account)
+                  Billing.const_get(\"Payment\")
+                  \"not a constant\".constantize
+                end
+              end
+              class LineItem; end
+            end",
+        );
+        let dynamic: Vec<&str> = rf
+            .relations
+            .iter()
+            .filter(|r| r.origin == Origin::Dynamic)
+            .map(|r| r.resolved.as_str())
+            .collect();
+        assert_eq!(
+            dynamic,
+            vec![
+                "Billing::LineItem",
+                "Reports::Ledger",
+                "Account",
+                "Billing::Ledger",
+                "Reports::Export",
+                "Account",
+                "Billing::Payment"
+            ]
+        );
+        assert!(rf
+            .relations
+            .iter()
+            .any(|r| r.resolved == "Reports::Export" && r.origin != Origin::Dynamic));
+    }
+
+    #[test]
+    fn infers_rails_associations_when_enabled() {
+        let source = "module Billing
+              class Invoice < ApplicationRecord
+                belongs_to :account
+                belongs_to :owner, polymorphic: true
+                has_many :line_items
+                has_one :profile, class_name: \"InvoiceProfile\"
+              end
+              class LineItem < ApplicationRecord; end
+            end";
+        let inferred = |rf: &RubyFile| -> Vec<String> {
+            rf.relations
+                .iter()
+                .filter(|r| r.origin == Origin::Inferred)
+                .map(|r| r.resolved.clone())
+                .collect()
+        };
+        assert!(inferred(&parse(source)).is_empty());
+        let rf = parse_with(source, &Options { rails: true });
+        assert_eq!(inferred(&rf), vec!["Account", "Billing::LineItem"]);
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        let rf = ruby_file(
+            path::PathBuf::from("broken.rb"),
+            b"class Foo\n  def bar(\nend\n",
+            &Options::default(),
+        )
+        .unwrap();
+        let errors: Vec<String> = rf
+            .diagnostics
+            .iter()
+            .filter(|d| d.error)
+            .map(|d| d.to_string())
+            .collect();
+        assert!(!errors.is_empty());
+        assert!(errors[0].starts_with("broken.rb:"), "{}", errors[0]);
     }
 
-    Ok(())
+    #[test]
+    fn worker_reports_unreadable_files_and_keeps_going() {
+        let dir = std::env::temp_dir().join(format!("constant_sandbox_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let readable = dir.join("readable.rb");
+        std::fs::write(&readable, "class Readable; end").unwrap();
+
+        let (work_tx, work_rx) = crossbeam_channel::unbounded();
+        let (results_tx, results_rx) = crossbeam_channel::unbounded();
+        work_tx.send(dir.join("missing.rb")).unwrap();
+        work_tx.send(readable).unwrap();
+        drop(work_tx);
+        worker(work_rx, results_tx, Options::default());
+
+        let mut codebase = Codebase::default();
+        for result in results_rx.iter() {
+            codebase.add(result);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(codebase.errors.len(), 1);
+        assert!(codebase.errors[0].file.ends_with("missing.rb"));
+        assert_eq!(codebase.definitions.len(), 1);
+    }
+
+    #[test]
+    fn lists_matching_files_once() {
+        let dir =
+            std::env::temp_dir().join(format!("constant_sandbox_sources_{}", std::process::id()));
+        for sub in &["lib/tasks", "lib/dir.rb", "vendor"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for file in &[
+            "lib/a.rb",
+            "lib/b.rake",
+            "lib/tasks/Rakefile",
+            "vendor/c.rb",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("/nonexistent", dir.join("lib/broken.rb")).unwrap();
+
+        let sources = Sources {
+            patterns: vec![
+                String::from("**/*.rb"),
+                String::from("lib/*.rb"),
+                String::from("**/*.rake"),
+            ],
+            filenames: vec![String::from("Rakefile")],
+            exclude: vec![String::from("vendor/**")],
+        };
+        let (files, errors) = sources.files_in(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut files: Vec<String> = files
+            .iter()
+            .map(|f| f.strip_prefix(&dir).unwrap().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        let mut expected = vec!["lib/a.rb", "lib/b.rake", "lib/tasks/Rakefile"];
+        if cfg!(unix) {
+            expected.insert(2, "lib/broken.rb");
+        }
+        assert_eq!(files, expected);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn reports_parser_panics_as_errors() {
+        let dir =
+            std::env::temp_dir().join(format!("constant_sandbox_panic_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("bad.rb");
+        std::fs::write(&file, b"def f\xff\xfe; end\n").unwrap();
+        let result = analyze(&file, &Options::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            result.err().as_deref(),
+            Some("the parser panicked on this file")
+        );
+        assert!(!PARSING.with(|parsing| parsing.get()));
+    }
+
+    #[test]
+    fn records_spans_in_characters() {
+        let rf = parse("module Café\n  X = \"é\"; Billing::Invoice\nend\n");
+        assert_eq!(
+            rf.definitions[0].span,
+            Span {
+                start_line: 1,
+                start_column: 1,
+                end_line: 3,
+                end_column: 4
+            }
+        );
+        assert_eq!(
+            rf.relations[0].span,
+            Span {
+                start_line: 2,
+                start_column: 12,
+                end_line: 2,
+                end_column: 28
+            }
+        );
+    }
+
+    #[test]
+    fn records_suppression_comments() {
+        let mut rf = parse(
+            "module Billing\n  class Ledger; end\n  # constant_sandbox:disable-next-line Billing::Ledger -- legacy report\n  Ledger.new; Invoice.new\n  # constant_sandbox:disable\n  Invoice.new\n  # constant_sandbox:enable\n  Invoice.new\n  # constant_sandbox:ignore Invoice\nend\n",
+        );
+        assert_eq!(rf.suppressions.len(), 2);
+        assert_eq!(rf.suppressions[0].lines, (4, 4));
+        assert_eq!(rf.suppressions[0].constants, vec!["Billing::Ledger"]);
+        assert_eq!(rf.suppressions[0].reason.as_deref(), Some("legacy report"));
+        assert_eq!(rf.suppressions[1].lines, (6, 7));
+        assert!(rf.suppressions[1].constants.is_empty());
+        assert_eq!(rf.suppressions[1].reason, None);
+        assert!(rf.diagnostics[0]
+            .message
+            .starts_with("unknown directive constant_sandbox:ignore"));
+
+        suppress(&rf.suppressions, &mut rf.relations);
+        let suppressed: Vec<Option<usize>> = rf
+            .relations
+            .iter()
+            .map(|r| r.suppressed_by.map(|s| s.start_line))
+            .collect();
+        assert_eq!(suppressed, vec![Some(3), None, Some(5), None]);
+    }
 }
@@ -0,0 +1,148 @@
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Constant a Rails association macro such as `has_many :line_items` points at,
+/// before it is resolved against the model's namespace.
+pub fn association_class(macro_name: &str, association: &str) -> Option<String> {
+    match macro_name {
+        "belongs_to" | "has_one" => Some(camelize(association)),
+        "has_many" | "has_and_belongs_to_many" => Some(camelize(&singularize(association))),
+        _ => None,
+    }
+}
+
+/// `ActiveSupport::Inflector#camelize`: `admin/line_item` becomes `Admin::LineItem`.
+pub fn camelize(word: &str) -> String {
+    word.split('/')
+        .map(|part| {
+            part.split('_')
+                .map(|piece| {
+                    let mut chars = piece.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>()
+        .join("::")
+}
+
+const UNCOUNTABLE: &[&str] = &[
+    "equipment",
+    "information",
+    "rice",
+    "money",
+    "species",
+    "series",
+    "fish",
+    "sheep",
+    "jeans",
+    "police",
+];
+
+const IRREGULAR: &[(&str, &str)] = &[
+    ("people", "person"),
+    ("men", "man"),
+    ("children", "child"),
+    ("sexes", "sex"),
+    ("moves", "move"),
+    ("zombies", "zombie"),
+];
+
+/// ActiveSupport's default singular rules, most specific first.
+const SINGULAR: &[(&str, &str)] = &[
+    (r"(?i)(database)s$", "$1"),
+    (r"(?i)(quiz)zes$", "$1"),
+    (r"(?i)(matr)ices$", "${1}ix"),
+    (r"(?i)(vert|ind)ices$", "${1}ex"),
+    (r"(?i)^(ox)en", "$1"),
+    (r"(?i)(alias|status)(es)?$", "$1"),
+    (r"(?i)(octop|vir)(us|i)$", "${1}us"),
+    (r"(?i)^(a)x[ie]s$", "${1}xis"),
+    (r"(?i)(cris|test)(is|es)$", "${1}is"),
+    (r"(?i)(shoe)s$", "$1"),
+    (r"(?i)(o)es$", "$1"),
+    (r"(?i)(bus)(es)?$", "$1"),
+    (r"(?i)^(m|l)ice$", "${1}ouse"),
+    (r"(?i)(x|ch|ss|sh)es$", "$1"),
+    (r"(?i)(m)ovies$", "${1}ovie"),
+    (r"(?i)(s)eries$", "${1}eries"),
+    (r"(?i)([^aeiouy]|qu)ies$", "${1}y"),
+    (r"(?i)([lr])ves$", "${1}f"),
+    (r"(?i)(tive)s$", "$1"),
+    (r"(?i)(hive)s$", "$1"),
+    (r"(?i)([^f])ves$", "${1}fe"),
+    (r"(?i)(^analy)(sis|ses)$", "${1}sis"),
+    (
+        r"(?i)((a)naly|(b)a|(d)iagno|(p)arenthe|(p)rogno|(s)ynop|(t)he)(sis|ses)$",
+        "${1}sis",
+    ),
+    (r"(?i)([ti])a$", "${1}um"),
+    (r"(?i)(n)ews$", "${1}ews"),
+    (r"(?i)(ss)$", "$1"),
+    (r"(?i)s$", ""),
+];
+
+/// `ActiveSupport::Inflector#singularize` with the default English rules.
+/// Only the last `_` separated word is inflected: `line_items` becomes `line_item`.
+pub fn singularize(word: &str) -> String {
+    let (prefix, last) = match word.rfind('_') {
+        Some(i) => word.split_at(i + 1),
+        None => ("", word),
+    };
+    let lower = last.to_lowercase();
+    if UNCOUNTABLE.contains(&lower.as_str()) {
+        return word.to_owned();
+    }
+    if let Some((_, singular)) = IRREGULAR.iter().find(|(plural, _)| *plural == lower) {
+        return format!("{}{}", prefix, singular);
+    }
+
+    static RULES: OnceLock<Vec<(Regex, &str)>> = OnceLock::new();
+    let rules = RULES.get_or_init(|| {
+        SINGULAR
+            .iter()
+            .map(|(pattern, replacement)| (Regex::new(pattern).unwrap(), *replacement))
+            .collect()
+    });
+    for (rule, replacement) in rules {
+        if rule.is_match(last) {
+            return format!("{}{}", prefix, rule.replace(last, *replacement));
+        }
+    }
+    word.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infers_association_classes() {
+        let cases = [
+            ("belongs_to", "account", "Account"),
+            ("has_one", "billing_profile", "BillingProfile"),
+            ("has_many", "orders", "Order"),
+            ("has_many", "line_items", "LineItem"),
+            ("has_many", "categories", "Category"),
+            ("has_many", "addresses", "Address"),
+            ("has_many", "people", "Person"),
+            ("has_many", "statuses", "Status"),
+            ("has_many", "analyses", "Analysis"),
+            ("has_and_belongs_to_many", "series", "Series"),
+            ("has_many", "admin/users", "Admin::User"),
+        ];
+        for (macro_name, association, class) in cases.iter() {
+            assert_eq!(
+                association_class(macro_name, association).as_deref(),
+                Some(*class),
+                "{} :{}",
+                macro_name,
+                association
+            );
+        }
+        assert_eq!(association_class("validates", "name"), None);
+    }
+}
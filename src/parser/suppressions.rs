@@ -0,0 +1,207 @@
+use super::{Diagnostic, Relation, Span};
+use std::collections::{HashMap, HashSet};
+use std::path;
+
+static PREFIX: &str = "constant_sandbox:";
+
+/// Comments that suppressed a violation, by file and span.
+pub type UsedSuppressions = HashSet<(path::PathBuf, Span)>;
+
+/// A comment turning off checks for some references:
+/// `# constant_sandbox:disable-next-line Billing::Ledger -- reason` for the
+/// line below it, or a `disable` ... `enable` pair for the lines between.
+#[derive(Clone)]
+pub struct Suppression {
+    pub file: path::PathBuf,
+    /// Where the comment is.
+    pub span: Span,
+    /// First and last line the comment applies to.
+    pub lines: (usize, usize),
+    /// Constants whose references are not checked. Every constant when empty.
+    pub constants: Vec<String>,
+    /// Why the references are fine, written after `--`.
+    pub reason: Option<String>,
+}
+
+impl Suppression {
+    /// Whether `rel` is one of the references the comment is about.
+    pub fn covers(&self, rel: &Relation) -> bool {
+        rel.file == self.file
+            && (self.lines.0..=self.lines.1).contains(&rel.span.start_line)
+            && (self.constants.is_empty()
+                || self
+                    .constants
+                    .iter()
+                    .any(|c| *c == rel.resolved || *c == rel.namespace))
+    }
+}
+
+/// The suppressions among the `comments` of `file`, each given with its span
+/// and text, and warnings for directives that are not understood.
+/// `last_line` closes a `disable` without `enable`.
+pub fn extract(
+    file: &path::Path,
+    comments: &[(Span, String)],
+    last_line: usize,
+) -> (Vec<Suppression>, Vec<Diagnostic>) {
+    let mut suppressions = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut open: Vec<Suppression> = Vec::new();
+    for (span, text) in comments {
+        let directive = match text.trim_start_matches('#').trim().strip_prefix(PREFIX) {
+            Some(directive) => directive,
+            None => continue,
+        };
+        let (directive, reason) = match directive.split_once("--") {
+            Some((directive, reason)) => (directive, Some(reason.trim())),
+            None => (directive, None),
+        };
+        let mut words = directive.split(|c: char| c.is_whitespace() || c == ',');
+        let name = words.next().unwrap_or_default();
+        let suppression = Suppression {
+            file: file.to_path_buf(),
+            span: *span,
+            lines: (span.start_line + 1, span.start_line + 1),
+            constants: words
+                .filter(|w| !w.is_empty())
+                .map(|w| w.trim_start_matches("::").to_owned())
+                .collect(),
+            reason: reason.filter(|r| !r.is_empty()).map(String::from),
+        };
+        match name {
+            "disable-next-line" => suppressions.push(suppression),
+            "disable" => open.push(suppression),
+            "enable" => {
+                for mut block in open.drain(..) {
+                    block.lines.1 = span.start_line;
+                    suppressions.push(block);
+                }
+            }
+            _ => diagnostics.push(Diagnostic {
+                file: file.to_path_buf(),
+                span: *span,
+                message: format!(
+                    "unknown directive {}{}, expected disable-next-line, disable or enable",
+                    PREFIX, name
+                ),
+                error: false,
+            }),
+        }
+    }
+    for mut block in open {
+        block.lines.1 = last_line;
+        suppressions.push(block);
+    }
+    (suppressions, diagnostics)
+}
+
+/// Marks every relation covered by one of `suppressions` with the span of
+/// the comment suppressing it. Runs after `resolve`, so comments can name
+/// constants by their full name.
+pub fn suppress(suppressions: &[Suppression], rels: &mut [Relation]) {
+    let mut by_file: HashMap<&path::Path, Vec<&Suppression>> = HashMap::new();
+    for suppression in suppressions {
+        by_file
+            .entry(suppression.file.as_path())
+            .or_default()
+            .push(suppression);
+    }
+    for rel in rels.iter_mut() {
+        let found = by_file
+            .get(rel.file.as_path())
+            .and_then(|s| s.iter().find(|s| s.covers(rel)));
+        rel.suppressed_by = found.map(|s| s.span);
+    }
+}
+
+/// The `suppressions` without a reason or that suppress nothing, given the
+/// comments that were `used`, with what is wrong with them.
+pub fn suppression_problems<'a, I>(
+    suppressions: I,
+    used: &UsedSuppressions,
+) -> Vec<(&'a Suppression, &'static str)>
+where
+    I: IntoIterator<Item = &'a Suppression>,
+{
+    let mut problems = Vec::new();
+    for suppression in suppressions {
+        if suppression.reason.is_none() {
+            problems.push((suppression, "suppression without a reason"));
+        }
+        if !used.contains(&(suppression.file.clone(), suppression.span)) {
+            problems.push((suppression, "suppression that suppresses nothing"));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(line: usize, text: &str) -> (Span, String) {
+        let span = Span {
+            start_line: line,
+            start_column: 3,
+            end_line: line,
+            end_column: 3 + text.chars().count(),
+        };
+        (span, text.to_owned())
+    }
+
+    fn extract_from(comments: &[(Span, String)]) -> Vec<Suppression> {
+        let (suppressions, diagnostics) = extract(path::Path::new("lib/a.rb"), comments, 12);
+        assert!(diagnostics.is_empty());
+        suppressions
+    }
+
+    #[test]
+    fn reads_directives() {
+        let suppressions = extract_from(&[
+            comment(
+                1,
+                "# constant_sandbox:disable Billing::Ledger, ::Billing::Invoice",
+            ),
+            comment(2, "# constant_sandbox:disable -- "),
+            comment(5, "# constant_sandbox:enable"),
+            comment(
+                7,
+                "# constant_sandbox:disable-next-line Accounts::Ledger -- owned by accounts",
+            ),
+            comment(9, "# constant_sandbox:disable Payment"),
+        ]);
+        let lines: Vec<(usize, usize)> = suppressions.iter().map(|s| s.lines).collect();
+        assert_eq!(lines, vec![(2, 5), (3, 5), (8, 8), (10, 12)]);
+        assert_eq!(
+            suppressions[0].constants,
+            vec!["Billing::Ledger", "Billing::Invoice"]
+        );
+        assert!(suppressions[1].constants.is_empty());
+        assert_eq!(suppressions[1].reason, None);
+        assert_eq!(suppressions[2].reason.as_deref(), Some("owned by accounts"));
+        assert_eq!(suppressions[3].constants, vec!["Payment"]);
+    }
+
+    #[test]
+    fn reports_unused_suppressions_and_missing_reasons() {
+        let suppressions = extract_from(&[
+            comment(1, "# constant_sandbox:disable-next-line A -- generated"),
+            comment(3, "# constant_sandbox:disable-next-line B"),
+            comment(5, "# constant_sandbox:disable-next-line C -- generated"),
+        ]);
+        let mut used = UsedSuppressions::new();
+        used.insert((path::PathBuf::from("lib/a.rb"), suppressions[0].span));
+        used.insert((path::PathBuf::from("lib/a.rb"), suppressions[1].span));
+        let problems: Vec<(usize, &str)> = suppression_problems(&suppressions, &used)
+            .into_iter()
+            .map(|(s, problem)| (s.span.start_line, problem))
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                (3, "suppression without a reason"),
+                (5, "suppression that suppresses nothing"),
+            ]
+        );
+    }
+}
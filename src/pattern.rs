@@ -0,0 +1,187 @@
+use crate::parser;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
+
+/// A pattern naming constants in `box.yml`.
+///
+/// Patterns match whole names, segment by segment: `Billing::Invoice` is that
+/// constant only, `Billing::*` the constants directly inside `Billing` and
+/// `Billing::**` everything nested in it at any depth. `regex:` followed by a
+/// regular expression keeps the old, unanchored matching.
+#[derive(Debug, Clone)]
+pub enum ConstPattern {
+    Exact(String),
+    Children(String),
+    Descendants(String),
+    Regex(Box<Regex>),
+}
+
+static REGEX_PREFIX: &str = "regex:";
+
+impl ConstPattern {
+    pub fn matches(&self, constant: &str) -> bool {
+        match self {
+            ConstPattern::Exact(name) => constant == name,
+            ConstPattern::Children(scope) => {
+                inside(scope, constant).is_some_and(|rest| !rest.contains("::"))
+            }
+            ConstPattern::Descendants(scope) => inside(scope, constant).is_some(),
+            ConstPattern::Regex(regex) => regex.is_match(constant),
+        }
+    }
+}
+
+/// The part of `constant` below `scope`, if it is nested in it. An empty scope
+/// is the top level.
+fn inside<'a>(scope: &str, constant: &'a str) -> Option<&'a str> {
+    if scope.is_empty() {
+        return Some(constant);
+    }
+    constant
+        .strip_prefix(scope)
+        .and_then(|rest| rest.strip_prefix("::"))
+}
+
+/// What is wrong with a regex that does not compile. Syntax errors span
+/// several lines and the last one says what is wrong.
+fn regex_error(error: &regex::Error) -> String {
+    let error = error.to_string();
+    let reason = error.lines().last().unwrap_or_default();
+    reason.trim_start_matches("error: ").to_owned()
+}
+
+impl FromStr for ConstPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ConstPattern, String> {
+        if let Some(regex) = s.strip_prefix(REGEX_PREFIX) {
+            return Regex::new(regex)
+                .map(|r| ConstPattern::Regex(Box::new(r)))
+                .map_err(|e| format!("invalid regex {:?}: {}", regex, regex_error(&e)));
+        }
+        let name = s.trim_start_matches("::");
+        let mut segments: Vec<&str> = name.split("::").collect();
+        let wildcard = match segments.last() {
+            Some(&"*") | Some(&"**") => segments.pop(),
+            _ => None,
+        };
+        if segments == [""] && wildcard.is_some() {
+            segments.clear();
+        }
+        if !segments.iter().all(|s| parser::is_constant_name(s)) {
+            return Err(format!(
+                "invalid constant pattern {:?}, expected a name like Billing::Invoice, \
+                 Billing::* or Billing::**, or a regex: prefix",
+                s
+            ));
+        }
+        let scope = segments.join("::");
+        Ok(match wildcard {
+            Some("*") => ConstPattern::Children(scope),
+            Some(_) => ConstPattern::Descendants(scope),
+            None => ConstPattern::Exact(scope),
+        })
+    }
+}
+
+impl std::fmt::Display for ConstPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConstPattern::Exact(name) => write!(f, "{}", name),
+            ConstPattern::Children(scope) if scope.is_empty() => write!(f, "*"),
+            ConstPattern::Children(scope) => write!(f, "{}::*", scope),
+            ConstPattern::Descendants(scope) if scope.is_empty() => write!(f, "**"),
+            ConstPattern::Descendants(scope) => write!(f, "{}::**", scope),
+            ConstPattern::Regex(regex) => write!(f, "{}{}", REGEX_PREFIX, regex.as_str()),
+        }
+    }
+}
+
+/// Written as a string in `box.yml`. Each pattern is parsed as it is read,
+/// so an invalid one is reported at its own position in the file.
+impl<'de> Deserialize<'de> for ConstPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ConstPattern, D::Error> {
+        deserializer.deserialize_str(PatternVisitor)
+    }
+}
+
+struct PatternVisitor;
+
+impl<'de> de::Visitor<'de> for PatternVisitor {
+    type Value = ConstPattern;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a constant pattern")
+    }
+
+    fn visit_str<E: de::Error>(self, pattern: &str) -> Result<ConstPattern, E> {
+        pattern.parse().map_err(E::custom)
+    }
+}
+
+impl Serialize for ConstPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, constant: &str) -> bool {
+        pattern.parse::<ConstPattern>().unwrap().matches(constant)
+    }
+
+    #[test]
+    fn matches_whole_segments() {
+        assert!(matches("A", "A"));
+        assert!(!matches("A", "ABTest"));
+        assert!(!matches("A", "Admin::Billing"));
+        assert!(!matches("A", "A::B"));
+        assert!(matches("::Billing::Invoice", "Billing::Invoice"));
+
+        assert!(matches("Billing::*", "Billing::Invoice"));
+        assert!(!matches("Billing::*", "Billing::Invoice::Line"));
+        assert!(!matches("Billing::*", "Billing"));
+        assert!(!matches("Billing::*", "BillingV2::Invoice"));
+
+        assert!(matches("Billing::**", "Billing::Invoice"));
+        assert!(matches("Billing::**", "Billing::Invoice::Line"));
+        assert!(!matches("Billing::**", "Billing"));
+        assert!(matches("*", "Billing"));
+        assert!(!matches("*", "Billing::Invoice"));
+        assert!(matches("**", "Billing::Invoice"));
+
+        assert!(matches("regex:^Billing(V2)?::", "BillingV2::Invoice"));
+        assert!(matches("regex:A", "ABTest"));
+    }
+
+    #[test]
+    fn round_trips_and_rejects_invalid_patterns() {
+        for s in &[
+            "Billing::Invoice",
+            "Billing::*",
+            "Billing::**",
+            "**",
+            "regex:^A",
+        ] {
+            assert_eq!(&s.parse::<ConstPattern>().unwrap().to_string(), s);
+        }
+        for s in &[
+            "billing",
+            "Billing::",
+            "Billing::*::Invoice",
+            "A.*",
+            "Ärger",
+            "regex:A(",
+        ] {
+            assert!(
+                s.parse::<ConstPattern>().is_err(),
+                "{} should be rejected",
+                s
+            );
+        }
+    }
+}
@@ -1,47 +1,442 @@
+use crate::config;
 use crate::parser;
-use regex::Regex;
+use crate::pattern::ConstPattern;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct RubyBox {
-    #[serde(with = "regex_array")]
-    pub imports: Vec<Regex>,
-    #[serde(with = "regex_array")]
-    pub exports: Vec<Regex>,
+    /// Name other boxes use in `dependencies`. Defaults to the box's directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub imports: Vec<ConstPattern>,
+    #[serde(default)]
+    pub exports: Vec<Export>,
+    /// Kinds of reference other boxes may make to exported constants. All kinds when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub export_kinds: Option<Vec<parser::RelationKind>>,
+    /// Kinds of reference this box may make to imported constants. All kinds when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import_kinds: Option<Vec<parser::RelationKind>>,
+    /// Whether constants named in strings, e.g. `"Billing::Invoice".constantize`,
+    /// are checked. Defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enforce_dynamic: Option<bool>,
+    /// Gems, as named in `Gemfile.lock`, whose constants this box may use.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gems: Vec<String>,
+    /// Boxes, by name, whose exported constants this box may use.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
+    /// What this box and the boxes nested in it may use of each other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nested_visibility: Option<NestedVisibility>,
+    /// Whether other boxes using constants this box does not export fail
+    /// `verify`. Defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enforce_privacy: Option<Enforcement>,
+    /// Whether this box using constants, gems or boxes it does not declare
+    /// fails `verify`. Defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enforce_dependencies: Option<Enforcement>,
+    /// Directory, relative to the box, whose constants are exported without
+    /// listing them. Defaults to `public/`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_path: Option<String>,
+    /// Layer of the box, one of the `layers` of the project configuration.
+    /// Boxes may only use constants of boxes in the same or lower layers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<String>,
+}
+
+pub static DEFAULT_PUBLIC_PATH: &str = "public/";
+
+impl RubyBox {
+    /// How violations in direction `dir` are treated.
+    pub fn enforcement(&self, dir: &ViolationDirection) -> Enforcement {
+        let setting = match dir {
+            ViolationDirection::NonExportedReference | ViolationDirection::FriendOnly(_) => {
+                self.enforce_privacy
+            }
+            _ => self.enforce_dependencies,
+        };
+        setting.unwrap_or(Enforcement::Enabled)
+    }
+}
+
+/// An entry of `exports`: a pattern, or a map that also limits which boxes
+/// may use the constants, e.g. `{constant: "Billing::Ledger", visible_to: [reporting]}`.
+pub struct Export {
+    pub pattern: ConstPattern,
+    /// Boxes, by name or directory, the constants are exported to. Every box
+    /// when empty.
+    pub visible_to: Vec<String>,
+}
+
+impl Export {
+    /// Whether the constants are exported to `user`, the box of the
+    /// referencing file if it has one.
+    fn visible_to(&self, user: Option<&Package>) -> bool {
+        self.visible_to.is_empty()
+            || user.is_some_and(|u| self.visible_to.iter().any(|v| u.is_known_as(v)))
+    }
+}
+
+impl From<ConstPattern> for Export {
+    fn from(pattern: ConstPattern) -> Export {
+        Export {
+            pattern,
+            visible_to: Vec::new(),
+        }
+    }
+}
+
+impl std::str::FromStr for Export {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Export, String> {
+        s.parse::<ConstPattern>().map(Export::from)
+    }
 }
 
-mod regex_array {
-    use regex::Regex;
-    use serde::{self, ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct FriendExport {
+    constant: ConstPattern,
+    visible_to: Vec<String>,
+}
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let mut res = Vec::new();
-        let v: Vec<String> = Vec::deserialize(deserializer)?;
-        for pattern in v {
-            res.push(Regex::new(&pattern).unwrap());
+impl Serialize for Export {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.visible_to.is_empty() {
+            return self.pattern.serialize(serializer);
+        }
+        FriendExport {
+            constant: self.pattern.clone(),
+            visible_to: self.visible_to.clone(),
         }
-        Ok(res)
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Export {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Export, D::Error> {
+        deserializer.deserialize_any(ExportVisitor)
     }
+}
+
+struct ExportVisitor;
+
+impl<'de> serde::de::Visitor<'de> for ExportVisitor {
+    type Value = Export;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a constant pattern or a map with constant and visible_to")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, pattern: &str) -> Result<Export, E> {
+        pattern.parse().map_err(E::custom)
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Export, A::Error> {
+        let friend = FriendExport::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+        Ok(Export {
+            pattern: friend.constant,
+            visible_to: friend.visible_to,
+        })
+    }
+}
+
+/// Written `true`, `false` or `warn` in `box.yml`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Enforcement {
+    Enabled,
+    Disabled,
+    /// Violations are reported but do not fail `verify`.
+    Warn,
+}
 
-    pub fn serialize<S>(regs: &Vec<Regex>, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut seq = serializer.serialize_seq(Some(regs.len()))?;
-        for reg in regs {
-            seq.serialize_element(reg.as_str())?;
+impl Serialize for Enforcement {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Enforcement::Enabled => serializer.serialize_bool(true),
+            Enforcement::Disabled => serializer.serialize_bool(false),
+            Enforcement::Warn => serializer.serialize_str("warn"),
         }
-        seq.end()
     }
 }
 
+impl<'de> Deserialize<'de> for Enforcement {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Enforcement, D::Error> {
+        deserializer.deserialize_any(EnforcementVisitor)
+    }
+}
+
+struct EnforcementVisitor;
+
+impl<'de> serde::de::Visitor<'de> for EnforcementVisitor {
+    type Value = Enforcement;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("true, false or warn")
+    }
+
+    fn visit_bool<E: serde::de::Error>(self, enabled: bool) -> Result<Enforcement, E> {
+        Ok(if enabled {
+            Enforcement::Enabled
+        } else {
+            Enforcement::Disabled
+        })
+    }
+
+    fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Enforcement, E> {
+        match value {
+            "warn" => Ok(Enforcement::Warn),
+            _ => Err(E::invalid_value(serde::de::Unexpected::Str(value), &self)),
+        }
+    }
+}
+
+/// How a box and the boxes in its subdirectories see each other.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NestedVisibility {
+    /// Like any two boxes, through imports, exports and dependencies.
+    #[default]
+    Isolated,
+    /// Each may use the other's exports without declaring them.
+    Exports,
+    /// Each may use every constant of the other.
+    All,
+}
+
+/// A box file found in the codebase, known by its name.
+pub struct Package {
+    pub name: String,
+    pub path: path::PathBuf,
+    pub ruby_box: RubyBox,
+}
+
+impl Package {
+    pub fn new(path: path::PathBuf, ruby_box: RubyBox) -> Package {
+        let name = match &ruby_box.name {
+            Some(name) => name.clone(),
+            None => default_name(&path),
+        };
+        Package {
+            name,
+            path,
+            ruby_box,
+        }
+    }
+
+    /// Directory holding the box's files.
+    pub fn dir(&self) -> &path::Path {
+        self.path.parent().unwrap_or_else(|| path::Path::new(""))
+    }
+
+    /// Directory whose constants are exported automatically.
+    pub fn public_dir(&self) -> path::PathBuf {
+        let public_path = self.ruby_box.public_path.as_deref();
+        self.dir().join(public_path.unwrap_or(DEFAULT_PUBLIC_PATH))
+    }
+
+    /// Whether `name`, from `dependencies` or `visible_to`, is this box's
+    /// name or directory.
+    fn is_known_as(&self, name: &str) -> bool {
+        self.name == name || self.dir() == path::Path::new(name.trim_end_matches('/'))
+    }
+
+    /// Whether `user`, the box of the referencing file, may make `rel` to one
+    /// of this box's constants. `public` holds the constants defined in
+    /// public directories.
+    fn exports(
+        &self,
+        rel: &parser::Relation,
+        public: &HashSet<&str>,
+        user: Option<&Package>,
+    ) -> bool {
+        (public.contains(rel.resolved.as_str())
+            || self
+                .ruby_box
+                .exports
+                .iter()
+                .any(|e| e.pattern.matches(&rel.resolved) && e.visible_to(user)))
+            && allows_kind(&self.ruby_box.export_kinds, rel.kind)
+    }
+
+    /// The boxes `rel` is exported to when it is only exported to some.
+    fn friends(&self, rel: &parser::Relation) -> Vec<String> {
+        if !allows_kind(&self.ruby_box.export_kinds, rel.kind) {
+            return Vec::new();
+        }
+        self.ruby_box
+            .exports
+            .iter()
+            .filter(|e| e.pattern.matches(&rel.resolved))
+            .flat_map(|e| e.visible_to.iter().cloned())
+            .collect()
+    }
+}
+
+/// The visibility between two boxes, set by the outer one when one is
+/// nested in the other.
+pub fn nested_visibility(a: &Package, b: &Package) -> NestedVisibility {
+    let outer = if b.dir().starts_with(a.dir()) {
+        a
+    } else if a.dir().starts_with(b.dir()) {
+        b
+    } else {
+        return NestedVisibility::Isolated;
+    };
+    outer.ruby_box.nested_visibility.unwrap_or_default()
+}
+
+/// The box owning `file`: the one whose `box.yml` is in the closest directory
+/// above it.
+fn closest<'a>(packages: &[&'a Package], file: &path::Path) -> Option<&'a Package> {
+    packages
+        .iter()
+        .copied()
+        .filter(|p| file.starts_with(p.dir()))
+        .max_by_key(|p| p.dir().components().count())
+}
+
+/// The box's directory with `/` separators, e.g. `app/models/billing`.
+fn default_name(path: &path::Path) -> String {
+    let dir = path.parent().unwrap_or_else(|| path::Path::new(""));
+    let parts: Vec<_> = dir
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    if parts.is_empty() {
+        String::from(".")
+    } else {
+        parts.join("/")
+    }
+}
+
+/// Problems between boxes: names used twice, dependencies on and exports to
+/// boxes that do not exist and layers missing from `layers`.
+pub fn check_packages(packages: &[Package], layers: &[String]) -> Vec<config::ConfigError> {
+    let mut errors = Vec::new();
+    let mut names: HashMap<&str, &path::Path> = HashMap::new();
+    for package in packages {
+        if let Some(other) = names.insert(&package.name, &package.path) {
+            errors.push(config::ConfigError {
+                file: package.path.clone(),
+                location: None,
+                message: format!(
+                    "box name {} is already used by {}",
+                    package.name,
+                    other.to_string_lossy()
+                ),
+            });
+        }
+    }
+    for package in packages {
+        for dependency in &package.ruby_box.dependencies {
+            if !names.contains_key(dependency.as_str()) {
+                errors.push(config::ConfigError {
+                    file: package.path.clone(),
+                    location: None,
+                    message: format!("unknown box {} in dependencies", dependency),
+                });
+            }
+        }
+        let friends = package.ruby_box.exports.iter().flat_map(|e| &e.visible_to);
+        for friend in friends {
+            if !packages.iter().any(|p| p.is_known_as(friend)) {
+                errors.push(config::ConfigError {
+                    file: package.path.clone(),
+                    location: None,
+                    message: format!("unknown box {} in visible_to", friend),
+                });
+            }
+        }
+        if let Some(layer) = &package.ruby_box.layer {
+            if !layers.contains(layer) {
+                errors.push(config::ConfigError {
+                    file: package.path.clone(),
+                    location: None,
+                    message: format!(
+                        "unknown layer {}, {} lists [{}]",
+                        layer,
+                        config::FILE_NAME,
+                        layers.join(", ")
+                    ),
+                });
+            }
+        }
+    }
+    errors
+}
+
+fn allows_kind(kinds: &Option<Vec<parser::RelationKind>>, kind: parser::RelationKind) -> bool {
+    match kinds {
+        Some(kinds) => kinds.contains(&kind),
+        None => true,
+    }
+}
+
+#[derive(PartialEq)]
 pub enum ViolationDirection {
     NonImportedReference,
     NonExportedReference,
+    /// A constant exported only to the listed boxes, used by another one.
+    FriendOnly(Vec<String>),
+    /// A constant from a gem the box does not list under `gems`.
+    UndeclaredGem,
+    /// A constant exported by the named box, which is not a dependency.
+    UndeclaredDependency(String),
+    /// A constant of a box in a higher layer than this one.
+    Layer {
+        layer: String,
+        dependency: String,
+        dependency_layer: String,
+    },
+}
+
+impl ViolationDirection {
+    /// Name used for the direction in `box_todo.yml`.
+    pub fn key(&self) -> ViolationKey {
+        match self {
+            ViolationDirection::NonImportedReference => ViolationKey::NonImported,
+            ViolationDirection::NonExportedReference => ViolationKey::NonExported,
+            ViolationDirection::FriendOnly(_) => ViolationKey::FriendOnly,
+            ViolationDirection::UndeclaredGem => ViolationKey::UndeclaredGem,
+            ViolationDirection::UndeclaredDependency(_) => ViolationKey::UndeclaredDependency,
+            ViolationDirection::Layer { .. } => ViolationKey::Layer,
+        }
+    }
+}
+
+/// A `ViolationDirection` without its details, as written in `box_todo.yml`.
+#[derive(Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationKey {
+    NonImported,
+    NonExported,
+    FriendOnly,
+    UndeclaredGem,
+    UndeclaredDependency,
+    Layer,
+}
+
+impl std::fmt::Display for ViolationKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ViolationKey::NonImported => write!(f, "non_imported"),
+            ViolationKey::NonExported => write!(f, "non_exported"),
+            ViolationKey::FriendOnly => write!(f, "friend_only"),
+            ViolationKey::UndeclaredGem => write!(f, "undeclared_gem"),
+            ViolationKey::UndeclaredDependency => write!(f, "undeclared_dependency"),
+            ViolationKey::Layer => write!(f, "layer"),
+        }
+    }
 }
 
 pub struct BoxViolation {
@@ -51,143 +446,405 @@ pub struct BoxViolation {
 
 impl std::fmt::Display for BoxViolation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.dir {
+        let mut qualifiers = Vec::new();
+        if self.rel.kind != parser::RelationKind::Reference {
+            qualifiers.push(self.rel.kind.to_string());
+        }
+        match self.rel.origin {
+            parser::Origin::Constant => {}
+            parser::Origin::Dynamic => qualifiers.push(String::from("dynamic")),
+            parser::Origin::Inferred => qualifiers.push(String::from("inferred")),
+        }
+        let kind = if qualifiers.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", qualifiers.join(", "))
+        };
+        match &self.dir {
             ViolationDirection::NonImportedReference => write!(
                 f,
-                "non imported reference {} found in {} on line {}",
-                self.rel.namespace,
+                "non imported reference {}{} found in {}:{}",
+                self.rel.resolved,
+                kind,
                 self.rel.file.to_str().unwrap(),
-                self.rel.line
+                self.rel.span
             ),
             ViolationDirection::NonExportedReference => write!(
                 f,
-                "non exported reference {} found in {} on line {}",
-                self.rel.namespace,
+                "non exported reference {}{} found in {}:{}",
+                self.rel.resolved,
+                kind,
+                self.rel.file.to_str().unwrap(),
+                self.rel.span
+            ),
+            ViolationDirection::FriendOnly(friends) => write!(
+                f,
+                "friend only reference {}{} found in {}:{}, {} is only exported to {}",
+                self.rel.resolved,
+                kind,
                 self.rel.file.to_str().unwrap(),
-                self.rel.line
+                self.rel.span,
+                self.rel.resolved,
+                friends.join(", ")
+            ),
+            ViolationDirection::UndeclaredGem => write!(
+                f,
+                "undeclared gem {} referenced as {}{} found in {}:{}",
+                self.rel.gem.as_deref().unwrap_or_default(),
+                self.rel.resolved,
+                kind,
+                self.rel.file.to_str().unwrap(),
+                self.rel.span
+            ),
+            ViolationDirection::UndeclaredDependency(_) => write!(
+                f,
+                "reference {}{} found in {}:{}",
+                self.rel.resolved,
+                kind,
+                self.rel.file.to_str().unwrap(),
+                self.rel.span
+            ),
+            ViolationDirection::Layer {
+                layer,
+                dependency,
+                dependency_layer,
+            } => write!(
+                f,
+                "reference {}{} from layer {} to box {} in higher layer {} found in {}:{}",
+                self.rel.resolved,
+                kind,
+                layer,
+                dependency,
+                dependency_layer,
+                self.rel.file.to_str().unwrap(),
+                self.rel.span
             ),
         }
     }
 }
 
+/// Lines describing the violations of `package`. References to boxes it does
+/// not depend on are listed under the box they use.
+pub fn report<'a, I>(package: &Package, violations: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a BoxViolation>,
+{
+    let mut lines = Vec::new();
+    let mut undeclared: BTreeMap<&str, Vec<&BoxViolation>> = BTreeMap::new();
+    for violation in violations {
+        match &violation.dir {
+            ViolationDirection::UndeclaredDependency(dependency) => {
+                undeclared.entry(dependency).or_default().push(violation)
+            }
+            _ => lines.push(violation.to_string()),
+        }
+    }
+    for (dependency, violations) in undeclared {
+        lines.push(format!(
+            "box {} uses box {} without declaring the dependency",
+            package.name, dependency
+        ));
+        for violation in violations {
+            lines.push(format!("  {}", violation));
+        }
+    }
+    lines
+}
+
+/// GitHub Actions workflow commands annotating each violation of `package`
+/// at `level`, `error` or `warning`.
+pub fn annotations<'a, I>(package: &Package, violations: I, level: &str) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a BoxViolation>,
+{
+    violations
+        .into_iter()
+        .map(|violation| {
+            let message = match &violation.dir {
+                ViolationDirection::UndeclaredDependency(dependency) => format!(
+                    "box {} uses box {} without declaring the dependency: {}",
+                    package.name, dependency, violation
+                ),
+                _ => violation.to_string(),
+            };
+            let rel = &violation.rel;
+            annotation(level, &rel.file, rel.span, &message)
+        })
+        .collect()
+}
+
+/// A GitHub Actions workflow command at `level` pointing at `span` in `file`.
+pub fn annotation(level: &str, file: &path::Path, span: parser::Span, message: &str) -> String {
+    format!(
+        "::{} file={},line={},col={},endLine={},endColumn={}::{}",
+        level,
+        escape_property(&file.to_string_lossy()),
+        span.start_line,
+        span.start_column,
+        span.end_line,
+        span.end_column,
+        escape_data(message)
+    )
+}
+
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
 pub fn parse(s: &str) -> Result<RubyBox, serde_yaml::Error> {
-    let b: RubyBox = match serde_yaml::from_str(&s) {
-        Ok(b) => b,
-        Err(_) => RubyBox {
-            imports: Vec::new(),
-            exports: Vec::new(),
-        },
-    };
-    Ok(b)
+    if s.trim().is_empty() {
+        return Ok(RubyBox::default());
+    }
+    serde_yaml::from_str(s)
+}
+
+/// Reads and validates the box file at `path`.
+pub fn load(path: &path::Path) -> Result<RubyBox, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    parse(&contents).map_err(|e| config::ConfigError::yaml(path, &e).into())
+}
+
+/// Which box each file and constant belongs to.
+pub struct Owners<'a> {
+    files: HashMap<&'a path::Path, Option<&'a Package>>,
+    constants: HashMap<&'a str, &'a Package>,
+}
+
+impl<'a> Owners<'a> {
+    /// Ownership between `package` and `packages`, for checking `package`
+    /// alone. `package` may or may not be one of `packages`.
+    pub fn new(
+        package: &'a Package,
+        packages: &'a [Package],
+        defs: &'a [parser::Definition],
+        rels: &'a [parser::Relation],
+    ) -> Owners<'a> {
+        let candidates: Vec<&Package> = std::iter::once(package)
+            .chain(packages.iter().filter(|p| p.path != package.path))
+            .collect();
+        Owners::of(&candidates, defs, rels)
+    }
+
+    /// Ownership between all of `packages`.
+    pub fn all(
+        packages: &'a [Package],
+        defs: &'a [parser::Definition],
+        rels: &'a [parser::Relation],
+    ) -> Owners<'a> {
+        let candidates: Vec<&Package> = packages.iter().collect();
+        Owners::of(&candidates, defs, rels)
+    }
+
+    fn of(
+        candidates: &[&'a Package],
+        defs: &'a [parser::Definition],
+        rels: &'a [parser::Relation],
+    ) -> Owners<'a> {
+        let mut files = HashMap::new();
+        let def_files = defs.iter().map(|d| d.file.as_path());
+        for file in def_files.chain(rels.iter().map(|r| r.file.as_path())) {
+            files
+                .entry(file)
+                .or_insert_with(|| closest(candidates, file));
+        }
+        // a constant defined by several boxes belongs to the one defining it
+        // in the first file by path, whatever order the files were parsed in
+        let mut first: HashMap<&str, (&path::Path, &Package)> = HashMap::new();
+        for def in defs {
+            if let Some(Some(owner)) = files.get(def.file.as_path()) {
+                let found = (def.file.as_path(), *owner);
+                first
+                    .entry(def.namespace.as_str())
+                    .and_modify(|f| {
+                        if found.0 < f.0 {
+                            *f = found
+                        }
+                    })
+                    .or_insert(found);
+            }
+        }
+        let constants = first.into_iter().map(|(c, (_, o))| (c, o)).collect();
+        Owners { files, constants }
+    }
+
+    pub fn file(&self, file: &path::Path) -> Option<&'a Package> {
+        self.files.get(file).copied().flatten()
+    }
+
+    pub fn constant(&self, constant: &str) -> Option<&'a Package> {
+        self.constants.get(constant).copied()
+    }
 }
 
-pub fn enforce_box<'a>(
-    box_path: &'a path::PathBuf,
-    ruby_box: RubyBox,
-    defs: &'a [parser::Definition],
-    rels: &'a [parser::Relation],
-    ignores: &'a [glob::Pattern],
+pub fn ignored(ignores: &[glob::Pattern], file: &path::Path) -> bool {
+    match file.to_str() {
+        Some(s) => ignores.iter().any(|g| g.matches(s)),
+        None => false,
+    }
+}
+
+/// Checks the references into and out of `package`. `owners` tells which box
+/// each file and constant belongs to.
+pub fn enforce_box(
+    package: &Package,
+    owners: &Owners,
+    defs: &[parser::Definition],
+    rels: &[parser::Relation],
+    ignores: &[glob::Pattern],
 ) -> Vec<BoxViolation> {
     let mut violations: Vec<BoxViolation> = Vec::new();
-    let box_dir_opt = box_path.parent();
-    let box_dir = match box_dir_opt {
-        None => return violations,
-        Some(d) => d,
-    };
+    let ruby_box = &package.ruby_box;
+
+    let owner = |file: &path::Path| owners.file(file);
+    let in_box = |file: &path::Path| owner(file).is_some_and(|o| o.path == package.path);
+
+    let defs_in_box: Vec<&parser::Definition> = defs.iter().filter(|d| in_box(&d.file)).collect();
 
-    let ref defs_in_box: Vec<&parser::Definition> = defs
+    let public: HashSet<&str> = defs
         .iter()
-        .filter(|d| d.file.starts_with(box_dir))
+        .filter(|d| owner(&d.file).is_some_and(|p| d.file.starts_with(p.public_dir())))
+        .map(|d| d.namespace.as_str())
+        .collect();
+
+    let enforce_dynamic = ruby_box.enforce_dynamic.unwrap_or(true);
+    let rels: Vec<&parser::Relation> = rels
+        .iter()
+        .filter(|r| enforce_dynamic || r.origin != parser::Origin::Dynamic)
         .collect();
 
     let rels_not_exported: Vec<&parser::Relation> = rels
         .iter()
+        .copied()
+        .filter(|r| !ignored(ignores, &r.file))
         .filter(|r| {
-            ignores.iter().all(|g| {
-                if let Some(s) = r.file.to_str() {
-                    !g.matches(s)
-                } else {
-                    true
-                }
-            })
-        })
-        .filter(|r| {
-            defs_in_box.iter().any(|d| d.namespace == r.namespace)
-                && !ruby_box.exports.iter().any(|b| b.is_match(&r.namespace))
+            !in_box(&r.file)
+                && defs_in_box.iter().any(|d| d.namespace == r.resolved)
+                && !package.exports(r, &public, owner(&r.file))
+                && !owner(&r.file)
+                    .is_some_and(|o| nested_visibility(package, o) == NestedVisibility::All)
         })
         .collect();
 
     for rel in rels_not_exported {
+        let friends = package.friends(rel);
+        let dir = if friends.is_empty() {
+            ViolationDirection::NonExportedReference
+        } else {
+            ViolationDirection::FriendOnly(friends)
+        };
         violations.push(BoxViolation {
             rel: rel.clone(),
-            dir: ViolationDirection::NonExportedReference,
+            dir,
         })
     }
 
     let rels_inside_box_not_imported: Vec<&parser::Relation> = rels
         .iter()
+        .copied()
         .filter(|r| {
-            r.file.starts_with(box_dir)
-                && !(ruby_box.imports.iter().any(|b| b.is_match(&r.namespace))
-                    || matches_to_self(r, defs_in_box))
+            in_box(&r.file)
+                && !((ruby_box.imports.iter().any(|b| b.matches(&r.resolved))
+                    && allows_kind(&ruby_box.import_kinds, r.kind))
+                    || r.gem.as_ref().is_some_and(|g| ruby_box.gems.contains(g))
+                    || defs_in_box.iter().any(|d| d.namespace == r.resolved))
         })
         .collect();
 
     for rel in rels_inside_box_not_imported {
+        let owner = owners
+            .constant(&rel.resolved)
+            .filter(|o| o.path != package.path);
+        let visibility = owner.map(|o| nested_visibility(package, o));
+        let dir = match owner {
+            _ if rel.gem.is_some() => ViolationDirection::UndeclaredGem,
+            Some(_) if visibility == Some(NestedVisibility::All) => continue,
+            Some(owner) if owner.exports(rel, &public, Some(package)) => {
+                if visibility == Some(NestedVisibility::Exports)
+                    || ruby_box.dependencies.contains(&owner.name)
+                {
+                    continue;
+                }
+                ViolationDirection::UndeclaredDependency(owner.name.clone())
+            }
+            _ => ViolationDirection::NonImportedReference,
+        };
         violations.push(BoxViolation {
             rel: rel.clone(),
-            dir: ViolationDirection::NonImportedReference,
+            dir,
         })
     }
 
+    violations.retain(|v| ruby_box.enforcement(&v.dir) != Enforcement::Disabled);
     violations
 }
 
-fn matches_to_self(rel: &parser::Relation, defs: &Vec<&parser::Definition>) -> bool {
-    let mut parts: Vec<&str> = rel.caller_namespace.split("::").collect();
-    parts.pop();
-    parts.push(&rel.namespace);
-    let ns1 = parts.join("::");
-
-    let mut parts: Vec<&str> = rel.caller_namespace.split("::").collect();
-    parts.push(&rel.namespace);
-    let ns2 = parts.join("::");
-
-    let mut parts: Vec<&str> = rel.caller_namespace.split("::").collect();
-    parts.pop();
-    parts.pop();
-    parts.push(&rel.namespace);
-    let ns3 = parts.join("::");
-
-    let mut parts: Vec<&str> = rel.caller_namespace.split("::").collect();
-    parts.pop();
-    parts.pop();
-    parts.pop();
-    parts.push(&rel.namespace);
-    let ns4 = parts.join("::");
-
-    defs.iter().any(|d| {
-        d.namespace == rel.namespace
-            || d.namespace == ns1
-            || d.namespace == ns2
-            || d.namespace == ns3
-            || d.namespace == ns4
-    })
+/// Checks that `package` only uses constants of boxes in its own or lower
+/// layers. `layers` is the project's layer order, lowest first.
+pub fn enforce_layers(
+    package: &Package,
+    owners: &Owners,
+    layers: &[String],
+    rels: &[parser::Relation],
+    ignores: &[glob::Pattern],
+) -> Vec<BoxViolation> {
+    let level = |p: &Package| {
+        let layer = p.ruby_box.layer.as_ref()?;
+        layers.iter().position(|l| l == layer)
+    };
+    let own_level = match level(package) {
+        Some(level) => level,
+        None => return Vec::new(),
+    };
+    let enforce_dynamic = package.ruby_box.enforce_dynamic.unwrap_or(true);
+    let mut violations = Vec::new();
+    for rel in rels {
+        let in_box = owners
+            .file(&rel.file)
+            .is_some_and(|o| o.path == package.path);
+        let checked = enforce_dynamic || rel.origin != parser::Origin::Dynamic;
+        if !in_box || !checked || ignored(ignores, &rel.file) {
+            continue;
+        }
+        let owner = match owners.constant(&rel.resolved) {
+            Some(owner) if owner.path != package.path => owner,
+            _ => continue,
+        };
+        if let Some(dependency_level) = level(owner).filter(|l| *l > own_level) {
+            violations.push(BoxViolation {
+                rel: rel.clone(),
+                dir: ViolationDirection::Layer {
+                    layer: layers[own_level].clone(),
+                    dependency: owner.name.clone(),
+                    dependency_layer: layers[dependency_level].clone(),
+                },
+            });
+        }
+    }
+    violations.retain(|v| package.ruby_box.enforcement(&v.dir) != Enforcement::Disabled);
+    violations
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::str::FromStr;
 
     struct BoxConstraintTest {
         name: String,
         box_path: path::PathBuf,
         ruby_box: RubyBox,
+        packages: Vec<Package>,
         ignores: Vec<glob::Pattern>,
+        layers: Vec<String>,
         defs: Vec<parser::Definition>,
         rels: Vec<parser::Relation>,
-        violations: Vec<BoxViolation>,
+        /// Expected violations, each with the index of its relation in `rels`.
+        violations: Vec<(ViolationDirection, usize)>,
     }
 
     impl BoxConstraintTest {
@@ -195,11 +852,10 @@ mod tests {
             BoxConstraintTest {
                 name: String::from(name),
                 box_path: path::PathBuf::from(box_path),
-                ruby_box: RubyBox {
-                    imports: Vec::new(),
-                    exports: Vec::new(),
-                },
+                ruby_box: RubyBox::default(),
+                packages: Vec::new(),
                 ignores: Vec::new(),
+                layers: Vec::new(),
                 defs: Vec::new(),
                 rels: Vec::new(),
                 violations: Vec::new(),
@@ -209,11 +865,22 @@ mod tests {
 
     impl parser::Relation {
         pub fn new(caller_namespace: &str, namespace: &str, file: &str) -> parser::Relation {
+            let mut nesting = Vec::new();
+            let mut scope = Vec::new();
+            for part in caller_namespace.split("::").filter(|p| !p.is_empty()) {
+                scope.push(part);
+                nesting.insert(0, scope.join("::"));
+            }
             parser::Relation {
-                caller_namespace: String::from(caller_namespace),
+                nesting,
                 namespace: String::from(namespace),
+                resolved: String::from(namespace),
                 file: path::PathBuf::from(file),
-                line: 0,
+                span: parser::Span::default(),
+                kind: parser::RelationKind::Reference,
+                origin: parser::Origin::Constant,
+                gem: None,
+                suppressed_by: None,
             }
         }
     }
@@ -223,12 +890,74 @@ mod tests {
             parser::Definition {
                 namespace: String::from(namespace),
                 file: path::PathBuf::from(file),
-                line: 0,
-                lines: 0,
+                span: parser::Span::default(),
             }
         }
     }
 
+    #[test]
+    fn rejects_invalid_boxes() {
+        let path = path::Path::new("lib/mod/box.yml");
+        let error = |s: &str| config::ConfigError::yaml(path, &parse(s).err().unwrap()).to_string();
+        assert!(parse("").is_ok());
+        assert!(parse("exports: [\"A\"]").is_ok());
+        assert!(error("imports: []\nexport:\n  - \"A\"\n")
+            .starts_with("lib/mod/box.yml:2:1: unknown field `export`"));
+        assert!(error("imports:\n  - \"A\"\n  - \"B(\"\n")
+            .starts_with("lib/mod/box.yml:3:5: imports[1]: invalid constant pattern \"B(\""));
+        assert!(error("imports:\n  - \"A\"\n exports: []\n").starts_with("lib/mod/box.yml:3:"));
+    }
+
+    #[test]
+    fn reads_enforcement_settings() {
+        let rb = parse("enforce_privacy: warn\nenforce_dependencies: false\n").unwrap();
+        assert_eq!(rb.enforce_privacy, Some(Enforcement::Warn));
+        assert_eq!(rb.enforce_dependencies, Some(Enforcement::Disabled));
+        assert_eq!(
+            rb.enforcement(&ViolationDirection::UndeclaredGem),
+            Enforcement::Disabled
+        );
+        assert!(parse("enforce_privacy: sometimes\n").is_err());
+        let yaml = serde_yaml::to_string(&rb).unwrap();
+        assert!(yaml.contains("enforce_privacy: warn"));
+        assert!(yaml.contains("enforce_dependencies: false"));
+    }
+
+    #[test]
+    fn reads_friend_exports() {
+        let rb = parse(
+            "exports:\n  - \"Billing::Invoice\"\n  - constant: \"Billing::Ledger\"\n    visible_to: [reporting]\n",
+        )
+        .unwrap();
+        assert!(rb.exports[0].visible_to.is_empty());
+        assert_eq!(rb.exports[1].visible_to, vec!["reporting"]);
+        let yaml = serde_yaml::to_string(&rb).unwrap();
+        assert!(yaml.contains("- constant: \"Billing::Ledger\""));
+        assert_eq!(parse(&yaml).unwrap().exports.len(), 2);
+
+        let path = path::Path::new("lib/mod/box.yml");
+        let error = |s: &str| config::ConfigError::yaml(path, &parse(s).err().unwrap()).to_string();
+        assert!(
+            error("exports:\n  - constant: \"B(\"\n    visible_to: []\n")
+                .starts_with("lib/mod/box.yml:2:15: exports[0].constant: invalid constant pattern")
+        );
+        assert!(error("exports:\n  - constant: B\n    visible: [a]\n")
+            .starts_with("lib/mod/box.yml:3:5: exports[0]: unknown field `visible`"));
+    }
+
+    #[test]
+    fn rejects_unknown_layers() {
+        let rb = parse("layer: domain\n").unwrap();
+        let packages = vec![Package::new("lib/mod/box.yml".into(), rb)];
+        let layers = vec![String::from("platform"), String::from("domain")];
+        assert!(check_packages(&packages, &layers).is_empty());
+        let errors = check_packages(&packages, &layers[..1]);
+        assert_eq!(
+            errors[0].to_string(),
+            "lib/mod/box.yml: unknown layer domain, constant_sandbox.yml lists [platform]"
+        );
+    }
+
     #[test]
     fn enforces_box_constraints() {
         let mut tests: Vec<BoxConstraintTest> = Vec::new();
@@ -238,10 +967,8 @@ mod tests {
                 .push(parser::Definition::new("A", "lib/mod/mod.rb"));
             test.rels
                 .push(parser::Relation::new("A", "Z", "lib/mod/mod.rb"));
-            test.violations.push(BoxViolation {
-                dir: ViolationDirection::NonImportedReference,
-                rel: test.rels[0].clone(),
-            });
+            test.violations
+                .push((ViolationDirection::NonImportedReference, 0));
             tests.push(test);
         }
         {
@@ -249,7 +976,7 @@ mod tests {
                 "single external reference defined import",
                 "lib/mod/box.yaml",
             );
-            test.ruby_box.imports.push(Regex::from_str("Z").unwrap());
+            test.ruby_box.imports.push("Z".parse().unwrap());
             test.defs
                 .push(parser::Definition::new("A", "lib/mod/mod.rb"));
             test.rels
@@ -264,15 +991,13 @@ mod tests {
                 .push(parser::Definition::new("B", "lib/mod2/mod.rb"));
             test.rels
                 .push(parser::Relation::new("B", "A", "lib/mod2/mod.rb"));
-            test.violations.push(BoxViolation {
-                dir: ViolationDirection::NonExportedReference,
-                rel: test.rels[0].clone(),
-            });
+            test.violations
+                .push((ViolationDirection::NonExportedReference, 0));
             tests.push(test);
         }
         {
             let mut test = BoxConstraintTest::new("single incoming reference", "lib/mod/box.yaml");
-            test.ruby_box.exports.push(Regex::from_str("A").unwrap());
+            test.ruby_box.exports.push("A".parse().unwrap());
             test.defs
                 .push(parser::Definition::new("A", "lib/mod/mod.rb"));
             test.defs
@@ -281,6 +1006,315 @@ mod tests {
                 .push(parser::Relation::new("B", "A", "lib/mod2/mod.rb"));
             tests.push(test);
         }
+        {
+            let mut test =
+                BoxConstraintTest::new("exported only for some kinds", "lib/mod/box.yaml");
+            test.ruby_box.exports.push("A".parse().unwrap());
+            test.ruby_box.export_kinds = Some(vec![parser::RelationKind::Inheritance]);
+            test.defs
+                .push(parser::Definition::new("A", "lib/mod/mod.rb"));
+            let mut subclass = parser::Relation::new("B", "A", "lib/mod2/mod.rb");
+            subclass.kind = parser::RelationKind::Inheritance;
+            test.rels.push(subclass);
+            let mut mixin = parser::Relation::new("C", "A", "lib/mod2/mod.rb");
+            mixin.kind = parser::RelationKind::Mixin;
+            test.rels.push(mixin);
+            test.violations
+                .push((ViolationDirection::NonExportedReference, 1));
+            tests.push(test);
+        }
+        {
+            let mut test =
+                BoxConstraintTest::new("dynamic references can be skipped", "lib/mod/box.yaml");
+            test.ruby_box.enforce_dynamic = Some(false);
+            test.defs
+                .push(parser::Definition::new("A", "lib/mod/mod.rb"));
+            let mut dynamic = parser::Relation::new("A", "Z", "lib/mod/mod.rb");
+            dynamic.origin = parser::Origin::Dynamic;
+            test.rels.push(dynamic);
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new("gems can be imported", "lib/mod/box.yaml");
+            test.ruby_box.gems.push(String::from("sidekiq"));
+            test.defs
+                .push(parser::Definition::new("A", "lib/mod/mod.rb"));
+            let mut worker = parser::Relation::new("A", "Sidekiq::Worker", "lib/mod/mod.rb");
+            worker.gem = Some(String::from("sidekiq"));
+            test.rels.push(worker);
+            let mut client = parser::Relation::new("A", "Faraday", "lib/mod/mod.rb");
+            client.gem = Some(String::from("faraday"));
+            test.rels.push(client);
+            test.violations.push((ViolationDirection::UndeclaredGem, 1));
+            tests.push(test);
+        }
+        {
+            let mut test =
+                BoxConstraintTest::new("dependencies import exports", "lib/mod/box.yaml");
+            test.ruby_box.dependencies.push(String::from("billing"));
+            let billing = RubyBox {
+                name: Some(String::from("billing")),
+                exports: vec!["B::**".parse().unwrap()],
+                ..Default::default()
+            };
+            test.packages
+                .push(Package::new("lib/billing/box.yml".into(), billing));
+            test.packages.push(Package::new(
+                "lib/accounts/box.yml".into(),
+                RubyBox::default(),
+            ));
+            test.defs
+                .push(parser::Definition::new("A", "lib/mod/mod.rb"));
+            test.defs.push(parser::Definition::new(
+                "B::Invoice",
+                "lib/billing/invoice.rb",
+            ));
+            test.defs.push(parser::Definition::new(
+                "C::Account",
+                "lib/accounts/account.rb",
+            ));
+            test.rels
+                .push(parser::Relation::new("A", "B::Invoice", "lib/mod/mod.rb"));
+            test.rels
+                .push(parser::Relation::new("A", "C::Account", "lib/mod/mod.rb"));
+            test.violations
+                .push((ViolationDirection::NonImportedReference, 1));
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new("undeclared dependency", "lib/mod/box.yaml");
+            let billing = RubyBox {
+                exports: vec!["B::Invoice".parse().unwrap()],
+                ..Default::default()
+            };
+            test.packages
+                .push(Package::new("lib/billing/box.yml".into(), billing));
+            test.defs
+                .push(parser::Definition::new("A", "lib/mod/mod.rb"));
+            test.defs.push(parser::Definition::new(
+                "B::Invoice",
+                "lib/billing/invoice.rb",
+            ));
+            test.rels
+                .push(parser::Relation::new("A", "B::Invoice", "lib/mod/mod.rb"));
+            test.violations.push((
+                ViolationDirection::UndeclaredDependency(String::from("lib/billing")),
+                0,
+            ));
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new("nested boxes own their files", "lib/box.yml");
+            test.packages.push(Package::new(
+                "lib/billing/box.yml".into(),
+                RubyBox::default(),
+            ));
+            test.defs.push(parser::Definition::new("A", "lib/a.rb"));
+            test.defs.push(parser::Definition::new(
+                "B::Invoice",
+                "lib/billing/invoice.rb",
+            ));
+            test.rels
+                .push(parser::Relation::new("A", "B::Invoice", "lib/a.rb"));
+            test.rels.push(parser::Relation::new(
+                "B::Invoice",
+                "A",
+                "lib/billing/invoice.rb",
+            ));
+            test.violations
+                .push((ViolationDirection::NonImportedReference, 0));
+            test.violations
+                .push((ViolationDirection::NonExportedReference, 1));
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new("nested boxes share exports", "lib/box.yml");
+            test.ruby_box.nested_visibility = Some(NestedVisibility::Exports);
+            test.ruby_box.exports.push("A".parse().unwrap());
+            let billing = RubyBox {
+                exports: vec!["B::Invoice".parse().unwrap()],
+                ..Default::default()
+            };
+            test.packages
+                .push(Package::new("lib/billing/box.yml".into(), billing));
+            test.defs.push(parser::Definition::new("A", "lib/a.rb"));
+            test.defs.push(parser::Definition::new("C", "lib/c.rb"));
+            test.defs.push(parser::Definition::new(
+                "B::Invoice",
+                "lib/billing/invoice.rb",
+            ));
+            test.defs
+                .push(parser::Definition::new("B::Line", "lib/billing/line.rb"));
+            test.rels
+                .push(parser::Relation::new("A", "B::Invoice", "lib/a.rb"));
+            test.rels
+                .push(parser::Relation::new("A", "B::Line", "lib/a.rb"));
+            test.rels.push(parser::Relation::new(
+                "B::Invoice",
+                "A",
+                "lib/billing/invoice.rb",
+            ));
+            test.rels.push(parser::Relation::new(
+                "B::Invoice",
+                "C",
+                "lib/billing/invoice.rb",
+            ));
+            test.violations
+                .push((ViolationDirection::NonImportedReference, 1));
+            test.violations
+                .push((ViolationDirection::NonExportedReference, 3));
+            tests.push(test);
+        }
+        {
+            let mut test =
+                BoxConstraintTest::new("nested boxes share everything", "lib/billing/box.yml");
+            let lib = RubyBox {
+                nested_visibility: Some(NestedVisibility::All),
+                ..Default::default()
+            };
+            test.packages.push(Package::new("lib/box.yml".into(), lib));
+            test.defs.push(parser::Definition::new("A", "lib/a.rb"));
+            test.defs.push(parser::Definition::new(
+                "B::Invoice",
+                "lib/billing/invoice.rb",
+            ));
+            test.rels
+                .push(parser::Relation::new("A", "B::Invoice", "lib/a.rb"));
+            test.rels.push(parser::Relation::new(
+                "B::Invoice",
+                "A",
+                "lib/billing/invoice.rb",
+            ));
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new("privacy checks can be off", "lib/mod/box.yaml");
+            test.ruby_box.enforce_privacy = Some(Enforcement::Disabled);
+            test.ruby_box.enforce_dependencies = Some(Enforcement::Warn);
+            test.defs
+                .push(parser::Definition::new("A", "lib/mod/mod.rb"));
+            test.defs
+                .push(parser::Definition::new("B", "lib/mod2/mod.rb"));
+            test.rels
+                .push(parser::Relation::new("B", "A", "lib/mod2/mod.rb"));
+            test.rels
+                .push(parser::Relation::new("A", "Z", "lib/mod/mod.rb"));
+            test.violations
+                .push((ViolationDirection::NonImportedReference, 1));
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new("public folder is exported", "lib/mod/box.yaml");
+            test.ruby_box.public_path = Some(String::from("api"));
+            test.defs.push(parser::Definition::new(
+                "A::Client",
+                "lib/mod/api/client.rb",
+            ));
+            test.defs
+                .push(parser::Definition::new("A::Pool", "lib/mod/public/pool.rb"));
+            test.rels
+                .push(parser::Relation::new("B", "A::Client", "lib/mod2/mod.rb"));
+            test.rels
+                .push(parser::Relation::new("B", "A::Pool", "lib/mod2/mod.rb"));
+            test.violations
+                .push((ViolationDirection::NonExportedReference, 1));
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new("friend exports", "lib/billing/box.yml");
+            test.ruby_box.exports.push(Export {
+                pattern: "B::Ledger".parse().unwrap(),
+                visible_to: vec![String::from("reporting"), String::from("lib/audit/")],
+            });
+            test.packages.push(Package::new(
+                "lib/reporting/box.yml".into(),
+                RubyBox {
+                    name: Some(String::from("reporting")),
+                    ..Default::default()
+                },
+            ));
+            test.packages
+                .push(Package::new("lib/audit/box.yml".into(), RubyBox::default()));
+            test.defs.push(parser::Definition::new(
+                "B::Ledger",
+                "lib/billing/ledger.rb",
+            ));
+            test.rels.push(parser::Relation::new(
+                "R",
+                "B::Ledger",
+                "lib/reporting/report.rb",
+            ));
+            test.rels
+                .push(parser::Relation::new("A", "B::Ledger", "lib/audit/log.rb"));
+            test.rels.push(parser::Relation::new(
+                "O",
+                "B::Ledger",
+                "lib/orders/order.rb",
+            ));
+            test.violations.push((
+                ViolationDirection::FriendOnly(vec![
+                    String::from("reporting"),
+                    String::from("lib/audit/"),
+                ]),
+                2,
+            ));
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new("lower layers", "lib/domain/box.yml");
+            test.layers = vec![
+                String::from("platform"),
+                String::from("domain"),
+                String::from("web"),
+            ];
+            test.ruby_box.layer = Some(String::from("domain"));
+            test.ruby_box.exports.push("D".parse().unwrap());
+            test.ruby_box.dependencies = vec![String::from("web"), String::from("platform")];
+            for (name, layer) in &[("web", "web"), ("platform", "platform")] {
+                let rb = RubyBox {
+                    name: Some(String::from(*name)),
+                    exports: vec!["**".parse().unwrap()],
+                    layer: Some(String::from(*layer)),
+                    ..Default::default()
+                };
+                test.packages
+                    .push(Package::new(format!("lib/{}/box.yml", name).into(), rb));
+            }
+            test.defs
+                .push(parser::Definition::new("D", "lib/domain/d.rb"));
+            test.defs.push(parser::Definition::new("W", "lib/web/w.rb"));
+            test.defs
+                .push(parser::Definition::new("P", "lib/platform/p.rb"));
+            test.rels
+                .push(parser::Relation::new("D", "W", "lib/domain/d.rb"));
+            test.rels
+                .push(parser::Relation::new("D", "P", "lib/domain/d.rb"));
+            test.rels
+                .push(parser::Relation::new("W", "D", "lib/web/w.rb"));
+            test.violations.push((
+                ViolationDirection::Layer {
+                    layer: String::from("domain"),
+                    dependency: String::from("web"),
+                    dependency_layer: String::from("web"),
+                },
+                0,
+            ));
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new("own files need no exports", "lib/mod/box.yaml");
+            test.defs
+                .push(parser::Definition::new("A::Secret", "lib/mod/secret.rb"));
+            test.defs
+                .push(parser::Definition::new("A::B", "lib/mod/b.rb"));
+            test.rels
+                .push(parser::Relation::new("A::B", "A::Secret", "lib/mod/b.rb"));
+            test.rels
+                .push(parser::Relation::new("C", "A::Secret", "lib/mod2/c.rb"));
+            test.violations
+                .push((ViolationDirection::NonExportedReference, 1));
+            tests.push(test);
+        }
         {
             let mut test = BoxConstraintTest::new("internal reference ok", "lib/mod/box.yaml");
             test.defs
@@ -291,6 +1325,33 @@ mod tests {
                 .push(parser::Relation::new("A", "B", "lib/mod/mod.rb"));
             tests.push(test);
         }
+        {
+            let mut test =
+                BoxConstraintTest::new("deeply nested internal reference ok", "lib/mod/box.yaml");
+            test.defs
+                .push(parser::Definition::new("A::F", "lib/mod/mod.rb"));
+            test.rels.push(parser::Relation::new(
+                "A::B::C::D::E",
+                "F",
+                "lib/mod/mod.rb",
+            ));
+            tests.push(test);
+        }
+        {
+            let mut test = BoxConstraintTest::new(
+                "short name resolves to the closest enclosing scope",
+                "lib/mod/box.yaml",
+            );
+            test.defs
+                .push(parser::Definition::new("Error", "lib/mod/mod.rb"));
+            test.defs
+                .push(parser::Definition::new("A::Error", "lib/mod2/mod.rb"));
+            test.rels
+                .push(parser::Relation::new("A::B", "Error", "lib/mod/mod.rb"));
+            test.violations
+                .push((ViolationDirection::NonImportedReference, 0));
+            tests.push(test);
+        }
         {
             let mut test = BoxConstraintTest::new("respect ignores", "lib/mod/box.yaml");
             test.ignores
@@ -303,14 +1364,18 @@ mod tests {
                 .push(parser::Relation::new("B", "A", "lib/mod2/mod.rb"));
             tests.push(test);
         }
-        for test in tests {
-            let results = enforce_box(
-                &test.box_path,
-                test.ruby_box,
-                &test.defs,
+        for mut test in tests {
+            parser::resolve(&test.defs, &mut test.rels);
+            let package = Package::new(test.box_path, test.ruby_box);
+            let owners = Owners::new(&package, &test.packages, &test.defs, &test.rels);
+            let mut results = enforce_box(&package, &owners, &test.defs, &test.rels, &test.ignores);
+            results.extend(enforce_layers(
+                &package,
+                &owners,
+                &test.layers,
                 &test.rels,
                 &test.ignores,
-            );
+            ));
             assert_eq!(
                 results.len(),
                 test.violations.len(),
@@ -319,13 +1384,13 @@ mod tests {
                 test.violations.len(),
                 results.len()
             );
-            for v in test.violations {
-                assert_eq!(
-                    results.iter().any(|r| r.rel == v.rel),
-                    true,
+            for (dir, index) in test.violations {
+                let rel = &test.rels[index];
+                assert!(
+                    results.iter().any(|r| r.dir == dir && r.rel == *rel),
                     "{}: expected to find {} but did not",
                     test.name,
-                    v.rel.namespace
+                    rel.resolved
                 );
             }
         }
@@ -0,0 +1,173 @@
+use crate::config;
+use crate::ruby_box;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path;
+
+pub static FILE_NAME: &str = "box_todo.yml";
+
+/// Violations a box already had when its todo list was written. `verify`
+/// only fails on violations that are not listed here.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Todo {
+    #[serde(default)]
+    pub violations: Vec<TodoEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TodoEntry {
+    pub constant: String,
+    /// Direction of the violation, e.g. `non_imported`.
+    pub violation: ruby_box::ViolationKey,
+    pub files: Vec<String>,
+}
+
+/// The todo file that goes with a box.
+pub fn path_for(package: &ruby_box::Package) -> path::PathBuf {
+    package.dir().join(FILE_NAME)
+}
+
+/// Reads the todo list at `path`, or an empty one when there is none.
+pub fn load(path: &path::Path) -> Result<Todo, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Ok(Todo::default());
+    }
+    let contents = std::fs::read_to_string(path)?;
+    if contents.trim().is_empty() {
+        return Ok(Todo::default());
+    }
+    serde_yaml::from_str(&contents).map_err(|e| config::ConfigError::yaml(path, &e).into())
+}
+
+fn file_key(violation: &ruby_box::BoxViolation) -> String {
+    violation.rel.file.to_string_lossy().replace('\\', "/")
+}
+
+impl Todo {
+    /// A todo list recording every one of `violations`.
+    pub fn from_violations(violations: &[ruby_box::BoxViolation]) -> Todo {
+        let mut grouped: BTreeMap<(&str, ruby_box::ViolationKey), BTreeSet<String>> =
+            BTreeMap::new();
+        for violation in violations {
+            grouped
+                .entry((&violation.rel.resolved, violation.dir.key()))
+                .or_default()
+                .insert(file_key(violation));
+        }
+        Todo {
+            violations: grouped
+                .into_iter()
+                .map(|((constant, violation), files)| TodoEntry {
+                    constant: constant.to_owned(),
+                    violation,
+                    files: files.into_iter().collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn entries(&self) -> HashSet<(&str, ruby_box::ViolationKey, &str)> {
+        let mut entries = HashSet::new();
+        for entry in &self.violations {
+            for file in &entry.files {
+                entries.insert((entry.constant.as_str(), entry.violation, file.as_str()));
+            }
+        }
+        entries
+    }
+
+    /// The violations not recorded in the todo list.
+    pub fn new_violations<'a>(
+        &self,
+        violations: &'a [ruby_box::BoxViolation],
+    ) -> Vec<&'a ruby_box::BoxViolation> {
+        let entries = self.entries();
+        violations
+            .iter()
+            .filter(|v| {
+                !entries.contains(&(v.rel.resolved.as_str(), v.dir.key(), file_key(v).as_str()))
+            })
+            .collect()
+    }
+
+    /// Recorded violations that no longer happen, as `(constant, violation, file)`.
+    pub fn stale_entries(
+        &self,
+        violations: &[ruby_box::BoxViolation],
+    ) -> Vec<(String, ruby_box::ViolationKey, String)> {
+        let current: HashSet<(String, ruby_box::ViolationKey, String)> = violations
+            .iter()
+            .map(|v| (v.rel.resolved.clone(), v.dir.key(), file_key(v)))
+            .collect();
+        let mut stale = Vec::new();
+        for entry in &self.violations {
+            for file in &entry.files {
+                let key = (entry.constant.clone(), entry.violation, file.clone());
+                if !current.contains(&key) {
+                    stale.push(key);
+                }
+            }
+        }
+        stale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn violation(constant: &str, file: &str) -> ruby_box::BoxViolation {
+        ruby_box::BoxViolation {
+            dir: ruby_box::ViolationDirection::NonImportedReference,
+            rel: parser::Relation::new("A", constant, file),
+        }
+    }
+
+    #[test]
+    fn recorded_violations_do_not_count() {
+        let recorded = vec![
+            violation("Billing::Invoice", "lib/mod/a.rb"),
+            violation("Billing::Invoice", "lib/mod/b.rb"),
+            violation("Billing::Payment", "lib/mod/a.rb"),
+        ];
+        let todo = Todo::from_violations(&recorded);
+        assert_eq!(todo.violations.len(), 2);
+        assert_eq!(
+            todo.violations[0].files,
+            vec!["lib/mod/a.rb", "lib/mod/b.rb"]
+        );
+
+        let yaml = serde_yaml::to_string(&todo).unwrap();
+        let todo: Todo = serde_yaml::from_str(&yaml).unwrap();
+
+        let current = vec![
+            violation("Billing::Invoice", "lib/mod/a.rb"),
+            violation("Billing::Invoice", "lib/mod/c.rb"),
+            violation("Billing::Payment", "lib/mod/a.rb"),
+        ];
+        let new: Vec<&str> = todo
+            .new_violations(&current)
+            .iter()
+            .map(|v| v.rel.file.to_str().unwrap())
+            .collect();
+        assert_eq!(new, vec!["lib/mod/c.rb"]);
+        assert_eq!(
+            todo.stale_entries(&current),
+            vec![(
+                String::from("Billing::Invoice"),
+                ruby_box::ViolationKey::NonImported,
+                String::from("lib/mod/b.rb")
+            )]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_violations() {
+        let yaml = "violations:\n  - constant: Billing::Invoice\n    violation: non_imprted\n    files: [lib/mod/a.rb]\n";
+        let error = serde_yaml::from_str::<Todo>(yaml).err().unwrap();
+        assert!(error.to_string().contains("unknown variant `non_imprted`"));
+    }
+}